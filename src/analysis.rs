@@ -0,0 +1,560 @@
+//! Static analysis of programs, shown next to each line in the editor. A
+//! program is traced once rather than run for every pixel, following each
+//! value on the stack back to the inputs and operators it was computed from.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::{BitOr, BitOrAssign, Range};
+
+use crate::program::{
+    Environment, INSTRUCTION_BUDGET, Instruction, MAX_CALL_DEPTH, Program, Stack, line_index,
+    loop_count, matching_loop_end,
+};
+
+/// A set of the things a value can depend on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Sources(u8);
+
+impl Sources {
+    pub const NONE: Sources = Sources(0);
+    pub const X: Sources = Sources(1);
+    pub const Y: Sources = Sources(2);
+    pub const T: Sources = Sources(4);
+    /// `r`
+    pub const RANDOM: Sources = Sources(8);
+    /// The audio operators
+    pub const AUDIO: Sources = Sources(16);
+    /// Anything kept from earlier frames: the previous frame, registers and
+    /// cells
+    pub const FEEDBACK: Sources = Sources(32);
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether any source is in both sets
+    pub fn intersects(self, other: Sources) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl BitOr for Sources {
+    type Output = Sources;
+
+    fn bitor(self, other: Sources) -> Sources {
+        Sources(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Sources {
+    fn bitor_assign(&mut self, other: Sources) {
+        self.0 |= other.0;
+    }
+}
+
+/// A letter for each source, in the order of their bits, after the
+/// operators that read them
+const SOURCE_NAMES: [char; 6] = ['x', 'y', 't', 'r', 'f', 'p'];
+
+impl fmt::Display for Sources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "-");
+        }
+        for (bit, name) in SOURCE_NAMES.iter().enumerate() {
+            if self.0 & 1 << bit != 0 {
+                write!(f, "{name}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One instruction executed by a program
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    /// The bytes of the source it came from. Everything run by a call comes
+    /// from the `#`
+    pub span: Range<usize>,
+    /// Number of values on the stack afterwards
+    pub depth: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Analysis {
+    /// Every instruction executed, in order, with loops unrolled
+    pub steps: Vec<Step>,
+    /// Number of pops that found the stack empty and took a value from the
+    /// ring buffer instead, including those taking the colour
+    pub underflows: usize,
+    /// What each value of the colour depends on, the first popped first
+    pub channels: [Sources; 3],
+    /// What the opacity depends on, if the program leaves one
+    pub alpha: Option<Sources>,
+    /// Parts of the source whose results are never used, because `.`
+    /// discards them or they are left on the stack under the colour
+    pub dead: Vec<Range<usize>>,
+}
+
+impl Analysis {
+    /// Everything the colour depends on
+    pub fn sources(&self) -> Sources {
+        self.channels
+            .iter()
+            .chain(&self.alpha)
+            .fold(Sources::NONE, |sources, &channel| sources | channel)
+    }
+
+    /// Whether the colour can differ between pixels or frames
+    pub fn varies(&self) -> bool {
+        !self.sources().is_empty()
+    }
+
+    /// Whether the colour depends on nothing but random numbers
+    pub fn is_noise(&self) -> bool {
+        self.sources() == Sources::RANDOM
+    }
+
+    /// A single flat colour or pure noise, neither of which is worth
+    /// showing
+    pub fn is_degenerate(&self) -> bool {
+        !self.varies() || self.is_noise()
+    }
+
+    /// Whether the colour changes across the frame and over time, other than
+    /// by being random. Earlier frames count as both
+    pub fn varies_over_space_and_time(&self) -> bool {
+        let sources = self.sources();
+        !self.is_degenerate()
+            && sources.intersects(Sources::X | Sources::Y | Sources::FEEDBACK)
+            && sources.intersects(Sources::T | Sources::AUDIO | Sources::FEEDBACK)
+    }
+
+    /// The shallowest and deepest the stack gets, if anything runs
+    pub fn depths(&self) -> Option<(usize, usize)> {
+        let depths = self.steps.iter().map(|step| step.depth);
+        Some((depths.clone().min()?, depths.max()?))
+    }
+}
+
+/// A summary such as `depth 3-5, 1 underflow, xy/t/x, 2 dead`, with the
+/// sources of each channel in the order they are popped
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((min, max)) = self.depths() {
+            write!(f, "depth {min}-{max}, ")?;
+        }
+        let plural = if self.underflows == 1 { "" } else { "s" };
+        write!(f, "{} underflow{plural}, ", self.underflows)?;
+        let [a, b, c] = self.channels;
+        write!(f, "{a}/{b}/{c}")?;
+        if let Some(alpha) = self.alpha {
+            write!(f, "/{alpha}")?;
+        }
+        if !self.dead.is_empty() {
+            write!(f, ", {} dead", self.dead.len())?;
+        }
+        if !self.varies() {
+            write!(f, ", constant")?;
+        } else if self.is_noise() {
+            write!(f, ", noise")?;
+        }
+        Ok(())
+    }
+}
+
+/// A value computed while tracing
+struct Node {
+    sources: Sources,
+    /// The value, if it is the same for every pixel and frame
+    constant: Option<f32>,
+    /// The nodes it was computed from
+    args: Vec<usize>,
+    /// Where in the source it was computed, unless it is an input
+    span: Option<Range<usize>>,
+}
+
+/// The stack of a program being traced, holding node indices. Like the ring
+/// buffer in the VM, an underflowing pop yields x, y and t in turn.
+struct Tracer<'a> {
+    nodes: Vec<Node>,
+    stack: Vec<usize>,
+    ring_position: usize,
+    /// Lines that `#` can call, which are traced as if written in place
+    lines: &'a [Program],
+    call_depth: usize,
+    budget: u32,
+    /// The span of the instruction being traced, or of the `#` that called
+    /// the line being traced
+    span: Range<usize>,
+    steps: Vec<Step>,
+    underflows: usize,
+    /// Nodes used other than by the colour: stored values, loop counts and
+    /// called line numbers
+    used: Vec<usize>,
+    /// Sources of loop counts and line numbers that weren't constant, which
+    /// decide what runs and so affect everything
+    control: Sources,
+    /// For working out constants with the VM's own operators
+    environment: Environment,
+}
+
+impl<'a> Tracer<'a> {
+    fn new(lines: &'a [Program]) -> Tracer<'a> {
+        Tracer {
+            // Nodes 0, 1 and 2 are x, y and t
+            nodes: vec![input(Sources::X), input(Sources::Y), input(Sources::T)],
+            stack: vec![2, 1, 0],
+            ring_position: 0,
+            lines,
+            call_depth: 0,
+            budget: INSTRUCTION_BUDGET,
+            span: 0..0,
+            steps: Vec::new(),
+            underflows: 0,
+            used: Vec::new(),
+            control: Sources::NONE,
+            environment: Environment::default(),
+        }
+    }
+
+    fn push(&mut self, node: usize) {
+        self.stack.push(node);
+    }
+
+    fn pop(&mut self) -> usize {
+        match self.stack.pop() {
+            Some(node) => node,
+            None => {
+                self.underflows += 1;
+                let node = self.ring_position;
+                self.ring_position = (self.ring_position + 1) % 3;
+                node
+            }
+        }
+    }
+
+    fn add(&mut self, sources: Sources, constant: Option<f32>, args: Vec<usize>) -> usize {
+        self.nodes.push(Node {
+            sources,
+            constant,
+            args,
+            span: Some(self.span.clone()),
+        });
+        self.nodes.len() - 1
+    }
+
+    fn step(&mut self) {
+        self.steps.push(Step {
+            span: self.span.clone(),
+            depth: self.stack.len(),
+        });
+    }
+
+    fn run(&mut self, instructions: &[Instruction], spans: &[Range<usize>]) {
+        let mut loops: Vec<(usize, u32)> = Vec::new();
+        let mut pc = 0;
+        while pc < instructions.len() && self.budget > 0 {
+            self.budget -= 1;
+            if self.call_depth == 0 {
+                self.span = spans[pc].clone();
+            }
+            match instructions[pc] {
+                Instruction::LoopStart => {
+                    let node = self.pop();
+                    self.used.push(node);
+                    // A count that varies is traced as a single iteration
+                    let count = match self.nodes[node].constant {
+                        Some(count) => loop_count(count),
+                        None => {
+                            self.control |= self.nodes[node].sources;
+                            1
+                        }
+                    };
+                    if count == 0 {
+                        pc = matching_loop_end(instructions, pc);
+                    } else {
+                        loops.push((pc + 1, count));
+                    }
+                }
+                Instruction::LoopEnd => {
+                    if let Some((start, remaining)) = loops.last_mut() {
+                        *remaining -= 1;
+                        if *remaining > 0 {
+                            pc = *start;
+                            self.step();
+                            continue;
+                        }
+                        loops.pop();
+                    }
+                }
+                instruction => self.execute(instruction),
+            }
+            self.step();
+            pc += 1;
+        }
+    }
+
+    fn execute(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Literal(value) => {
+                let node = self.add(Sources::NONE, Some(value), Vec::new());
+                self.push(node);
+            }
+            Instruction::LoopStart | Instruction::LoopEnd => {}
+            Instruction::Swap => {
+                let (val1, val2) = (self.pop(), self.pop());
+                self.push(val1);
+                self.push(val2);
+            }
+            Instruction::Duplicate => {
+                let val = self.pop();
+                self.push(val);
+                self.push(val);
+            }
+            Instruction::Pop => {
+                self.pop();
+            }
+            Instruction::Tuck => {
+                let (val1, val2) = (self.pop(), self.pop());
+                self.push(val1);
+                self.push(val2);
+                self.push(val1);
+            }
+            Instruction::DuplicateUnder => {
+                let (val1, val2) = (self.pop(), self.pop());
+                self.push(val2);
+                self.push(val2);
+                self.push(val1);
+            }
+            Instruction::LockBrightness => {
+                let (val, val2) = (self.pop(), self.pop());
+                let one = self.add(Sources::NONE, Some(1.0), Vec::new());
+                self.push(one);
+                self.push(val2);
+                self.push(val);
+            }
+            Instruction::PreviousFrame => {
+                let (x, y) = (self.pop(), self.pop());
+                let sources = Sources::FEEDBACK | self.nodes[x].sources | self.nodes[y].sources;
+                for _ in 0..3 {
+                    let node = self.add(sources, None, vec![x, y]);
+                    self.push(node);
+                }
+            }
+            Instruction::Store => {
+                let (register, value) = (self.pop(), self.pop());
+                self.used.extend([register, value]);
+            }
+            Instruction::Call => {
+                let node = self.pop();
+                self.used.push(node);
+                let Some(index) = self.nodes[node].constant else {
+                    // Which line runs varies, so none is traced
+                    self.control |= self.nodes[node].sources;
+                    return;
+                };
+                let lines = self.lines;
+                if self.call_depth < MAX_CALL_DEPTH
+                    && let Some(i) = line_index(index, lines.len())
+                {
+                    self.call_depth += 1;
+                    self.run(lines[i].instructions(), lines[i].spans());
+                    self.call_depth -= 1;
+                }
+            }
+            instruction => {
+                let (arity, sources) = operator(instruction);
+                self.apply(instruction, arity, sources);
+            }
+        }
+    }
+
+    /// Pop `arity` arguments and push the result of `instruction`, which
+    /// also depends on `sources`
+    fn apply(&mut self, instruction: Instruction, arity: usize, sources: Sources) {
+        let args: Vec<usize> = (0..arity).map(|_| self.pop()).collect();
+        let constants: Option<Vec<f32>> =
+            args.iter().map(|&arg| self.nodes[arg].constant).collect();
+        let constant = constants.filter(|_| sources.is_empty()).map(|constants| {
+            let mut stack = Stack::new([0.0; 3], &self.environment);
+            for &constant in constants.iter().rev() {
+                stack.push(constant);
+            }
+            stack.execute(instruction);
+            stack.pop()
+        });
+        let sources = args
+            .iter()
+            .fold(sources, |sources, &arg| sources | self.nodes[arg].sources);
+        let node = self.add(sources, constant, args);
+        self.push(node);
+    }
+
+    /// Nodes that the colour, or anything else kept, depends on
+    fn live(&self, outputs: &[usize]) -> Vec<bool> {
+        let mut live = vec![false; self.nodes.len()];
+        let mut pending: Vec<usize> = outputs.iter().chain(&self.used).copied().collect();
+        while let Some(node) = pending.pop() {
+            if !live[node] {
+                live[node] = true;
+                pending.extend(&self.nodes[node].args);
+            }
+        }
+        live
+    }
+}
+
+/// A value the program starts with
+fn input(sources: Sources) -> Node {
+    Node {
+        sources,
+        constant: None,
+        args: Vec::new(),
+        span: None,
+    }
+}
+
+/// How many values an operator pops, and what its result depends on other
+/// than those values
+fn operator(instruction: Instruction) -> (usize, Sources) {
+    match instruction {
+        Instruction::Negate
+        | Instruction::Sqrt
+        | Instruction::Abs
+        | Instruction::Floor
+        | Instruction::Fract => (1, Sources::NONE),
+        Instruction::Select
+        | Instruction::ValueNoise3
+        | Instruction::PerlinNoise3
+        | Instruction::SimplexNoise3 => (3, Sources::NONE),
+        Instruction::Fbm => (4, Sources::NONE),
+        Instruction::Random => (2, Sources::RANDOM),
+        Instruction::Frequency => (1, Sources::AUDIO),
+        Instruction::Bass | Instruction::Mid | Instruction::Treble => (0, Sources::AUDIO),
+        Instruction::Load | Instruction::Blur | Instruction::Laplacian | Instruction::Sobel => {
+            (1, Sources::FEEDBACK)
+        }
+        Instruction::Cell => (2, Sources::FEEDBACK),
+        Instruction::Neighbours => (0, Sources::FEEDBACK),
+        _ => (2, Sources::NONE),
+    }
+}
+
+/// Trace `program`, along with any of `lines` it calls
+pub fn analyse(program: &Program, lines: &[Program]) -> Analysis {
+    let mut tracer = Tracer::new(lines);
+    if program.is_automaton() {
+        // The cell's state
+        tracer.nodes.push(input(Sources::FEEDBACK));
+        tracer.push(tracer.nodes.len() - 1);
+    }
+    tracer.run(program.instructions(), program.spans());
+
+    let outputs = [tracer.pop(), tracer.pop(), tracer.pop()];
+    let alpha = tracer.stack.pop().filter(|_| program.colour().alpha);
+    // Values the colour model ignores are popped but not used
+    let used = &outputs[..program.colour().model.channels()];
+    let control = tracer.control;
+    let mut channels = [Sources::NONE; 3];
+    for (channel, &node) in channels.iter_mut().zip(used) {
+        *channel = tracer.nodes[node].sources | control;
+    }
+    let alpha_sources = alpha.map(|node| tracer.nodes[node].sources | control);
+
+    // A part of the source is dead if nothing computed there is used
+    let live = tracer.live(&[used, alpha.as_slice()].concat());
+    let mut spans: BTreeMap<(usize, usize), bool> = BTreeMap::new();
+    for (node, live) in tracer.nodes.iter().zip(live) {
+        if let Some(span) = &node.span {
+            *spans.entry((span.start, span.end)).or_default() |= live;
+        }
+    }
+    let dead = spans
+        .into_iter()
+        .filter(|(_, live)| !live)
+        .map(|((start, end), _)| start..end)
+        .collect();
+
+    Analysis {
+        steps: tracer.steps,
+        underflows: tracer.underflows,
+        channels,
+        alpha: alpha_sources,
+        dead,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colour::{ColourModel, ColourOutput};
+
+    #[test]
+    fn test_sources_of_each_channel() {
+        // x + y, then t, then an underflow wraps around to x
+        let analysis = analyse(&Program::compile("+"), &[]);
+        assert_eq!(
+            analysis.channels,
+            [Sources::X | Sources::Y, Sources::T, Sources::X]
+        );
+        assert_eq!(analysis.underflows, 1);
+        assert_eq!(analysis.depths(), Some((2, 2)));
+        assert!(analysis.varies());
+        assert_eq!(analysis.to_string(), "depth 2-2, 1 underflow, xy/t/x");
+
+        let analysis = analyse(&Program::compile("r0 360 1 1"), &[]);
+        assert_eq!(
+            analysis.channels,
+            [Sources::RANDOM, Sources::NONE, Sources::NONE]
+        );
+    }
+
+    #[test]
+    fn test_constant_programs() {
+        assert!(!analyse(&Program::compile("1 1 60"), &[]).varies());
+        let noise = analyse(&Program::compile("r0 360 1 1"), &[]);
+        assert!(noise.is_noise() && noise.is_degenerate());
+        assert!(noise.to_string().ends_with(", noise"));
+        // Operators on constants are worked out, so the loop count is known
+        let analysis = analyse(&Program::compile("[1+]+2 3 0 0 0"), &[]);
+        assert!(!analysis.varies());
+        assert_eq!(analysis.to_string().rsplit(", ").next(), Some("constant"));
+        // A count that depends on x makes everything depend on x
+        let analysis = analyse(&Program::compile("0 0 0[1]"), &[]);
+        assert_eq!(analysis.channels, [Sources::X; 3]);
+    }
+
+    #[test]
+    fn test_channels_the_palette_ignores() {
+        let program = Program::compile("5 6 +");
+        assert!(analyse(&program, &[]).varies());
+
+        // Only the position is used, so x + y and the 6 are dead
+        let palette = program.with_colour(ColourOutput {
+            model: ColourModel::Palette,
+            ..Default::default()
+        });
+        let analysis = analyse(&palette, &[]);
+        assert_eq!(analysis.channels, [Sources::NONE; 3]);
+        assert!(analysis.is_degenerate());
+        assert_eq!(analysis.dead, [2..3, 4..5]);
+        assert_eq!(analysis.to_string().rsplit(", ").next(), Some("constant"));
+    }
+
+    #[test]
+    fn test_popped_results_are_dead() {
+        let analysis = analyse(&Program::compile("+.*60"), &[]);
+        // `60` is multiplied with x, and the product is popped
+        assert_eq!(analysis.dead, [2..3, 3..5]);
+        assert_eq!(
+            analysis.steps[0],
+            Step {
+                span: 3..5,
+                depth: 4
+            }
+        );
+        // Stored values are used, even if never loaded
+        assert!(analyse(&Program::compile("!0*60"), &[]).dead.is_empty());
+    }
+}
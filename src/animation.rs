@@ -1,7 +1,13 @@
 use std::collections::HashSet;
 
-use crate::program;
+use crate::program::Environment;
+use crate::program::Program;
+use crate::programset::LineSettings;
+use crate::programset::Transition;
 use crate::utils;
+use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 use raylib::core::color::Color;
 
 struct FadeAnimation {}
@@ -9,13 +15,17 @@ struct FadeAnimation {}
 impl FadeAnimation {
     fn execute(
         &self,
-        current_program: String,
-        next_program: String,
+        current_program: &Program,
+        next_program: &Program,
         initial_values: [f32; 3],
+        environment: &Environment,
         f: f32,
     ) -> Color {
-        let c1 = program::execute_string_to_color(current_program.as_str(), initial_values);
-        let c2 = program::execute_string_to_color(next_program.as_str(), initial_values);
+        // Both programs run on every pixel, so only the one fading in
+        // stores to the registers and cells, or `!` would happen twice a
+        // frame and both lines would set each cell's next state
+        let c1 = current_program.execute_to_color_read_only(initial_values, environment);
+        let c2 = next_program.execute_to_color(initial_values, environment);
         c1.lerp(c2, f)
     }
 }
@@ -29,36 +39,42 @@ struct DissolveAnimation {
 
 impl DissolveAnimation {
     pub fn new(width: i32, height: i32, should_finish_in_time: f32) -> Self {
-        let pixels_needed_per_second =
-            ((width * height) as f32 / should_finish_in_time).round() as u32;
-        Self {
+        let mut animation = Self {
             width,
             height,
             pixels_seen: HashSet::new(),
-            pixels_needed_per_second,
-        }
+            pixels_needed_per_second: 0,
+        };
+        animation.set_duration(should_finish_in_time);
+        animation
+    }
+
+    fn set_duration(&mut self, should_finish_in_time: f32) {
+        self.pixels_needed_per_second =
+            ((self.width * self.height) as f32 / should_finish_in_time).round() as u32;
     }
 
     fn execute(
         &self,
-        current_program: String,
-        next_program: String,
+        current_program: &Program,
+        next_program: &Program,
         x: i32,
         y: i32,
         t: f32,
+        environment: &Environment,
     ) -> Color {
         let initial_values = [x as f32, y as f32, t];
 
         let i = y * self.width + x;
 
         if self.pixels_seen.contains(&i) {
-            program::execute_string_to_color(next_program.as_str(), initial_values)
+            next_program.execute_to_color(initial_values, environment)
         } else {
-            program::execute_string_to_color(current_program.as_str(), initial_values)
+            current_program.execute_to_color(initial_values, environment)
         }
     }
 
-    fn tick(&mut self, frame_time: f32) {
+    fn tick(&mut self, frame_time: f32, rng: &mut impl Rng) {
         let num_pixels_to_mark_seen =
             (frame_time * self.pixels_needed_per_second as f32).round() as usize;
         for _ in 0..num_pixels_to_mark_seen {
@@ -67,7 +83,7 @@ impl DissolveAnimation {
             }
             let mut i = -1;
             while self.pixels_seen.contains(&i) {
-                i = rand::random_range(0..self.width * self.height);
+                i = rng.random_range(0..self.width * self.height);
             }
             self.pixels_seen.insert(i);
         }
@@ -112,8 +128,8 @@ fn make_one_change(from: String, to: String) -> String {
 }
 
 struct ProgramDissolveAnimation {
-    current: String,
-    next_program: String,
+    current: Program,
+    next_program: Program,
     should_finish_in_time: f32,
     time_between_each_change: f32,
     t: f32,
@@ -122,62 +138,76 @@ struct ProgramDissolveAnimation {
 impl ProgramDissolveAnimation {
     fn new(should_finish_in_time: f32) -> Self {
         Self {
-            current: String::new(),
-            next_program: String::new(),
+            current: Program::default(),
+            next_program: Program::default(),
             should_finish_in_time,
             time_between_each_change: 0.0,
             t: 0.0,
         }
     }
 
-    fn execute(
-        &mut self,
-        current_program: String,
-        next_program: String,
-        x: i32,
-        y: i32,
-        t: f32,
-    ) -> Color {
+    fn prepare(&mut self, current_program: &Program, next_program: &Program) {
         if self.current.is_empty() {
             self.set_new_programs(current_program, next_program);
         }
+    }
 
+    fn execute(&self, x: i32, y: i32, t: f32, environment: &Environment) -> Color {
         let initial_values = [x as f32, y as f32, t];
 
-        program::execute_string_to_color(&self.current, initial_values)
+        self.current.execute_to_color(initial_values, environment)
     }
 
     fn tick(&mut self, frame_time: f32) {
         if self.t >= self.time_between_each_change {
-            self.current = make_one_change(self.current.clone(), self.next_program.clone());
+            self.current = self.current.with_source(make_one_change(
+                self.current.source().to_owned(),
+                self.next_program.source().to_owned(),
+            ));
             self.t = 0.0;
         }
         self.t += frame_time;
     }
 
-    fn set_new_programs(&mut self, current_program: String, next_program: String) {
+    fn set_new_programs(&mut self, current_program: &Program, next_program: &Program) {
         self.time_between_each_change = self.should_finish_in_time
-            / number_of_changes_needed(current_program.clone(), next_program.clone()) as f32;
-        self.current = current_program;
-        self.next_program = next_program;
+            / number_of_changes_needed(
+                current_program.source().to_owned(),
+                next_program.source().to_owned(),
+            ) as f32;
+        self.current = current_program.clone();
+        self.next_program = next_program.clone();
     }
 
     fn reset(&mut self) {
-        self.current.clear();
+        self.current = Program::default();
         self.t = 0.0;
     }
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum Animation {
     Fade,
     Dissolve,
     ProgramDissolve,
+    /// Hold the current program for the whole cycle, then switch
+    Cut,
+}
+
+impl From<Transition> for Animation {
+    fn from(transition: Transition) -> Self {
+        match transition {
+            Transition::Fade => Animation::Fade,
+            Transition::Dissolve => Animation::Dissolve,
+            Transition::ProgramDissolve => Animation::ProgramDissolve,
+            Transition::Cut => Animation::Cut,
+        }
+    }
 }
 
 impl Animation {
-    fn random() -> Self {
-        match rand::random_range(0..3) {
+    fn random(rng: &mut impl Rng) -> Self {
+        match rng.random_range(0..3) {
             0 => Animation::Fade,
             1 => Animation::Dissolve,
             _ => Animation::ProgramDissolve,
@@ -190,39 +220,99 @@ pub struct ProgramAnimator {
     fade_animation: FadeAnimation,
     dissolve_animation: DissolveAnimation,
     program_dissolve_animation: ProgramDissolveAnimation,
+    /// Chosen at random on every reset, for lines without a transition
+    random_animation: Animation,
     current_animation: Animation,
     t: f32,
+    /// Timing from config.toml, for lines that don't set their own
+    default_cycle_time: f32,
+    default_pause_fraction: f32,
     cycle_time: f32,
     pause_fraction: f32,
+    rng: StdRng,
 }
 
 impl ProgramAnimator {
-    pub fn new(cycle_time: f32, pause_fraction: f32, width: i32, height: i32) -> Self {
+    /// If `seed` is given, the choice of animations and the order pixels
+    /// dissolve in are reproducible
+    pub fn new(
+        cycle_time: f32,
+        pause_fraction: f32,
+        width: i32,
+        height: i32,
+        seed: Option<u64>,
+    ) -> Self {
         assert!(pause_fraction >= 0.0);
         assert!(pause_fraction < 1.0);
         let should_finish_in_time = cycle_time * (1.0 - pause_fraction);
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        let animation = Animation::random(&mut rng);
         Self {
             playing: true,
             fade_animation: FadeAnimation {},
             dissolve_animation: DissolveAnimation::new(width, height, should_finish_in_time),
             program_dissolve_animation: ProgramDissolveAnimation::new(should_finish_in_time),
-            current_animation: Animation::random(),
+            random_animation: animation,
+            current_animation: animation,
             t: 0.0,
+            default_cycle_time: cycle_time,
+            default_pause_fraction: pause_fraction,
             cycle_time,
             pause_fraction,
+            rng,
+        }
+    }
+
+    /// Take the timing and transition from the settings of the line being
+    /// shown. The line is held for `pause_fraction` of its cycle, then
+    /// transitions to the next line for the rest
+    pub fn configure(&mut self, settings: &LineSettings) {
+        self.cycle_time = settings.cycle_time.unwrap_or(self.default_cycle_time);
+        self.pause_fraction = settings
+            .pause_fraction
+            .unwrap_or(self.default_pause_fraction);
+        self.current_animation = settings
+            .transition
+            .map_or(self.random_animation, Animation::from);
+
+        let should_finish_in_time = self.cycle_time * (1.0 - self.pause_fraction);
+        self.dissolve_animation.set_duration(should_finish_in_time);
+        self.program_dissolve_animation.should_finish_in_time = should_finish_in_time;
+    }
+
+    /// Fraction of the cycle spent showing only the current program
+    fn hold_fraction(&self) -> f32 {
+        if self.current_animation == Animation::Cut {
+            1.0
+        } else {
+            self.pause_fraction
+        }
+    }
+
+    /// Update any animation state that depends on the programs being
+    /// animated. Must be called once per frame before rendering, since
+    /// `execute` is called from many threads at once
+    pub fn prepare(&mut self, current_program: &Program, next_program: &Program) {
+        if self.t > self.hold_fraction() && self.current_animation == Animation::ProgramDissolve {
+            self.program_dissolve_animation
+                .prepare(current_program, next_program);
         }
     }
 
     pub fn execute(
-        &mut self,
-        current_program: String,
-        next_program: String,
+        &self,
+        current_program: &Program,
+        next_program: &Program,
         x: i32,
         y: i32,
         t: f32,
+        environment: &Environment,
     ) -> Color {
-        if self.t <= self.pause_fraction {
-            program::execute_string_to_color(current_program.as_str(), [x as f32, y as f32, t])
+        if self.t <= self.hold_fraction() {
+            current_program.execute_to_color([x as f32, y as f32, t], environment)
         } else {
             let f = utils::map(self.pause_fraction, 1.0, 0.0, 1.0, self.t);
             match self.current_animation {
@@ -230,15 +320,23 @@ impl ProgramAnimator {
                     current_program,
                     next_program,
                     [x as f32, y as f32, t],
+                    environment,
                     f,
                 ),
-                Animation::Dissolve => {
-                    self.dissolve_animation
-                        .execute(current_program, next_program, x, y, t)
-                }
+                Animation::Dissolve => self.dissolve_animation.execute(
+                    current_program,
+                    next_program,
+                    x,
+                    y,
+                    t,
+                    environment,
+                ),
                 Animation::ProgramDissolve => {
                     self.program_dissolve_animation
-                        .execute(current_program, next_program, x, y, t)
+                        .execute(x, y, t, environment)
+                }
+                Animation::Cut => {
+                    current_program.execute_to_color([x as f32, y as f32, t], environment)
                 }
             }
         }
@@ -250,12 +348,16 @@ impl ProgramAnimator {
         to_line: usize,
         line_height: f32,
     ) -> f32 {
+        let hold_fraction = self.hold_fraction();
+        if hold_fraction >= 1.0 {
+            return from_line as f32 * line_height;
+        }
         utils::map(
-            self.pause_fraction,
+            hold_fraction,
             1.0,
             from_line as f32 * line_height,
             to_line as f32 * line_height,
-            self.t.clamp(self.pause_fraction, 1.0),
+            self.t.clamp(hold_fraction, 1.0),
         )
     }
 
@@ -277,10 +379,10 @@ impl ProgramAnimator {
         if self.playing {
             self.t += frame_time / self.cycle_time;
 
-            if self.t >= self.pause_fraction {
+            if self.t >= self.hold_fraction() {
                 match self.current_animation {
                     Animation::Dissolve => {
-                        self.dissolve_animation.tick(frame_time);
+                        self.dissolve_animation.tick(frame_time, &mut self.rng);
                     }
                     Animation::ProgramDissolve => {
                         self.program_dissolve_animation.tick(frame_time);
@@ -299,6 +401,57 @@ impl ProgramAnimator {
         self.t = 0.0;
         self.dissolve_animation.reset();
         self.program_dissolve_animation.reset();
-        self.current_animation = Animation::random();
+        self.random_animation = Animation::random(&mut self.rng);
+        self.current_animation = self.random_animation;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registers::Registers;
+
+    #[test]
+    fn test_line_settings_override_timing_and_transition() {
+        let mut animator = ProgramAnimator::new(20.0, 0.5, 4, 4, Some(0));
+        animator.configure(&LineSettings {
+            cycle_time: Some(2.0),
+            transition: Some(Transition::Cut),
+            ..Default::default()
+        });
+
+        animator.tick(1.5);
+        assert!(!animator.is_animation_finished());
+        // A cut holds the marker on the current line until the very end
+        assert_eq!(animator.calculate_marker_y_position(1, 3, 10.0), 10.0);
+
+        animator.tick(0.5);
+        assert!(animator.is_animation_finished());
+
+        // Lines without settings fall back to the config
+        animator.reset();
+        animator.configure(&LineSettings::default());
+        animator.tick(2.0);
+        assert!(!animator.is_animation_finished());
+    }
+
+    #[test]
+    fn test_fade_only_stores_registers_once_per_pixel() {
+        let mut animator = ProgramAnimator::new(2.0, 0.5, 4, 4, Some(0));
+        animator.configure(&LineSettings {
+            transition: Some(Transition::Fade),
+            ..Default::default()
+        });
+        animator.tick(1.5);
+        let environment = Environment {
+            registers: Registers::new(4, 4),
+            ..Default::default()
+        };
+
+        let count = Program::compile("!0d+1@0");
+        for _ in 0..3 {
+            animator.execute(&count, &count, 1, 1, 0.0, &environment);
+        }
+        assert_eq!(environment.registers.load(4 * 5, 0.0), 3.0);
     }
 }
@@ -1,19 +1,34 @@
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
-use std::sync::mpsc::Receiver;
-use std::sync::mpsc::Sender;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc;
 
+use crate::analysis;
 use crate::animation::ProgramAnimator;
+use crate::automaton::Cells;
+use crate::colour::ColourOutput;
+use crate::config::Config;
 use crate::drawing::draw_pause_button;
 use crate::drawing::draw_play_button;
+use crate::drawing::draw_text;
 use crate::program;
+use crate::program::Environment;
+use crate::program::Program;
+use crate::programset::ProgramSet;
+use crate::random::RandomMode;
 use crate::recorder;
 use crate::recorder::ScreenRecorder;
-use crate::recorder::ScreenRecorderMessage;
 use crate::recorder::ScreenRecorderState;
+use crate::registers::Registers;
+use crate::renderer::Frame;
+use crate::spectrum::Spectrum;
 use crate::texteditor;
 use crate::texteditor::TextEditor;
+use log::error;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use raylib::prelude::*;
 
 pub trait InputProvider {
@@ -52,6 +67,15 @@ pub trait InputProvider {
             let mut c = c as u32 as u8 as char;
 
             c.make_ascii_lowercase();
+            if self.is_key_down(KeyboardKey::KEY_LEFT_SHIFT)
+                || self.is_key_down(KeyboardKey::KEY_RIGHT_SHIFT)
+            {
+                c = match c {
+                    '/' => '?',
+                    '-' => '_',
+                    c => c,
+                };
+            }
             if c.is_ascii() {
                 return Some(s + &c.to_string());
             } else {
@@ -94,6 +118,20 @@ impl TimeProvider for RaylibHandle {
 pub struct AppState {
     text_editor: TextEditor,
     program_animator: ProgramAnimator,
+    current_program: Program,
+    next_program: Program,
+    environment: Environment,
+    /// Colour output for lines that don't set their own
+    colour: ColourOutput,
+    /// The colour output and automaton setting of each line when the lines
+    /// were last analysed
+    analysed_settings: Vec<(ColourOutput, bool)>,
+    /// Generations per second of automaton lines that don't set their own
+    step_rate: f32,
+    /// Time since the automaton last stepped
+    step_time: f32,
+    /// Draws the seed for each frame when `r` is in `frame` mode
+    frame_random: Option<StdRng>,
     pub screen_recorder: recorder::ScreenRecorder,
     pub screen_recorder_state: recorder::ScreenRecorderState,
     pub t: f32,
@@ -102,24 +140,46 @@ pub struct AppState {
 }
 
 impl AppState {
-    pub fn new(
-        screen_recorder_length: usize,
-        progress_sender: Sender<ScreenRecorderMessage>,
-        progress_receiver: Receiver<ScreenRecorderMessage>,
-        cycle_time: f32,
-        pause_fraction: f32,
-        primary_colour: impl Into<Color>,
-        width: i32,
-        height: i32,
-    ) -> Self {
+    pub fn new(config: &Config, width: i32, height: i32) -> Self {
+        let (progress_sender, progress_receiver) = mpsc::channel();
+        let mut text_editor = TextEditor::new();
+        text_editor.set_skip_degenerate(config.skip_degenerate);
+        text_editor.set_colour(config.colour_output());
+
         Self {
-            text_editor: TextEditor::new(),
-            program_animator: ProgramAnimator::new(cycle_time, pause_fraction, width, height),
-            screen_recorder: ScreenRecorder::new(screen_recorder_length, progress_sender),
+            text_editor,
+            program_animator: ProgramAnimator::new(
+                config.cycle_time,
+                config.pause_fraction,
+                width,
+                height,
+                config.seed,
+            ),
+            current_program: Program::default(),
+            next_program: Program::default(),
+            environment: Environment {
+                seed: match config.random_mode() {
+                    RandomMode::Hash => Some(config.seed.unwrap_or(0)),
+                    RandomMode::Thread | RandomMode::Frame => None,
+                },
+                registers: Registers::new(width, height),
+                cells: Cells::new(width, height, config.seed.unwrap_or(0)),
+                ..Default::default()
+            },
+            colour: config.colour_output(),
+            analysed_settings: Vec::new(),
+            step_rate: config.step_rate,
+            step_time: 0.0,
+            frame_random: match (config.random_mode(), config.seed) {
+                (RandomMode::Frame, Some(seed)) => Some(StdRng::seed_from_u64(seed)),
+                (RandomMode::Frame, None) => Some(StdRng::from_os_rng()),
+                _ => None,
+            },
+            screen_recorder: ScreenRecorder::new(config.video_frames as usize, progress_sender),
             screen_recorder_state: ScreenRecorderState::new(progress_receiver),
             t: 0.0,
             time_multiplier: 1.0,
-            primary_colour: primary_colour.into(),
+            primary_colour: config.primary_colour.into(),
         }
     }
 
@@ -130,21 +190,26 @@ impl AppState {
         if self.screen_recorder_state.is_saving() {
             self.screen_recorder_state.update();
         }
+        self.environment.cells.finish_step();
 
         if let Some(s) = provider.keystring() {
             match s.as_str() {
                 "C-s" => {
-                    if let Some(file) = rfd::FileDialog::new().save_file() {
+                    if let Some(file) = rfd::FileDialog::new()
+                        .add_filter("toml", &["toml"])
+                        .save_file()
+                    {
                         let mut file = File::create(file)?;
-                        write!(file, "{}", self.text_editor)?;
+                        write!(file, "{}", self.text_editor.program_set().to_toml()?)?;
                     }
                 }
                 "C-o" => {
-                    if let Some(file) = rfd::FileDialog::new().pick_file() {
-                        let mut file = File::open(file)?;
-                        let mut s = String::new();
-                        file.read_to_string(&mut s)?;
-                        self.text_editor.load_from_string(s);
+                    if let Some(path) = rfd::FileDialog::new().pick_file() {
+                        // A file that can't be loaded is reported and the
+                        // current programs kept, rather than quitting
+                        if let Err(e) = self.open_programs(&path) {
+                            error!("Couldn't open {}: {}", path.display(), e);
+                        }
                     }
                 }
                 "M-s" => {
@@ -160,6 +225,8 @@ impl AppState {
                 }
                 "C-t" => {
                     self.t = 0.0;
+                    self.environment.registers.clear();
+                    self.environment.cells.reset();
                 }
                 "C-r" => {
                     self.text_editor.randomise_line();
@@ -191,6 +258,12 @@ impl AppState {
                 "C-k" => {
                     self.text_editor.kill_to_end();
                 }
+                "C-/" | "C-_" => {
+                    self.text_editor.undo();
+                }
+                "C-?" => {
+                    self.text_editor.redo();
+                }
                 "M-<up>" => {
                     self.time_multiplier += 0.1;
                 }
@@ -205,8 +278,10 @@ impl AppState {
                     }
                 }
                 &_ => {
-                    if program::ALLOWED.contains(&s.chars().nth(0).unwrap_or('§')) {
-                        self.text_editor.insert_char(s.chars().nth(0).unwrap());
+                    let c = s.chars().nth(0).unwrap_or('§');
+                    let is_word = self.text_editor.words().definitions().contains_key(&c);
+                    if program::ALLOWED.contains(&c) || is_word {
+                        self.text_editor.insert_char(c);
                     }
                 }
             }
@@ -225,20 +300,161 @@ impl AppState {
         }
 
         if self.text_editor.num_non_empty_lines() >= 2 {
+            self.program_animator
+                .configure(self.text_editor.current_settings());
             self.program_animator.tick(provider.get_frame_time());
             if self.program_animator.is_animation_finished() {
                 self.text_editor.goto_next_nonempty();
                 self.program_animator.reset();
+                self.program_animator
+                    .configure(self.text_editor.current_settings());
             }
         }
 
-        self.t += provider.get_frame_time() * self.time_multiplier;
+        let line_multiplier = self
+            .text_editor
+            .current_settings()
+            .time_multiplier
+            .unwrap_or(1.0);
+        self.t += provider.get_frame_time() * self.time_multiplier * line_multiplier;
+        self.step_automaton(provider.get_frame_time());
+        if let Some(frame_random) = &mut self.frame_random {
+            self.environment.seed = Some(frame_random.random());
+        }
+
+        self.recompile_programs();
+        self.program_animator
+            .prepare(&self.current_program, &self.next_program);
 
         Ok(())
     }
 
-    pub fn current_input_line(&self) -> &str {
-        self.text_editor.get_current_line_str()
+    /// Step the automaton this frame if it is due, while the current or
+    /// next line is an automaton line
+    fn step_automaton(&mut self, frame_time: f32) {
+        let settings = [
+            self.text_editor.current_settings(),
+            self.text_editor.get_next_nonempty_settings(),
+        ];
+        let Some(settings) = settings.into_iter().find(|s| s.automaton == Some(true)) else {
+            self.step_time = 0.0;
+            return;
+        };
+        let step = 1.0 / settings.step_rate.unwrap_or(self.step_rate);
+        self.step_time += frame_time;
+        if self.step_time >= step {
+            // Never fall more than a step behind
+            self.step_time = (self.step_time - step).min(step);
+            self.environment.cells.begin_step();
+        }
+    }
+
+    /// Recompile the current and next programs, but only if their lines,
+    /// the words they use or their settings have changed since the last
+    /// frame
+    fn recompile_programs(&mut self) {
+        let words = self.text_editor.words();
+        let current = self.text_editor.get_current_line_str();
+        let settings = self.text_editor.current_settings();
+        let colour = self.colour.for_line(settings);
+        let automaton = settings.automaton.unwrap_or(false);
+        if current != self.current_program.source()
+            || !Arc::ptr_eq(words, self.current_program.words())
+            || colour != *self.current_program.colour()
+            || automaton != self.current_program.is_automaton()
+        {
+            self.current_program = Program::compile_with_words(current, words)
+                .with_colour(colour)
+                .with_automaton(automaton);
+        }
+        let next = self.text_editor.get_next_nonempty();
+        let settings = self.text_editor.get_next_nonempty_settings();
+        let colour = self.colour.for_line(settings);
+        let automaton = settings.automaton.unwrap_or(false);
+        if next != self.next_program.source()
+            || !Arc::ptr_eq(words, self.next_program.words())
+            || colour != *self.next_program.colour()
+            || automaton != self.next_program.is_automaton()
+        {
+            self.next_program = Program::compile_with_words(next, words)
+                .with_colour(colour)
+                .with_automaton(automaton);
+        }
+
+        // Analyses depend on how each line is drawn as well as its source
+        let settings: Vec<_> = self
+            .text_editor
+            .settings()
+            .iter()
+            .map(|settings| {
+                (
+                    self.colour.for_line(settings),
+                    settings.automaton.unwrap_or(false),
+                )
+            })
+            .collect();
+        let mut changed = settings != self.analysed_settings;
+
+        // Every line, for `#` to call
+        let lines = &mut self.environment.lines;
+        changed |= lines.len() != self.text_editor.lines().len();
+        lines.resize_with(self.text_editor.lines().len(), Program::default);
+        for (program, line) in lines.iter_mut().zip(self.text_editor.lines()) {
+            if line != program.source() || !Arc::ptr_eq(words, program.words()) {
+                *program = Program::compile_with_words(line.as_str(), words);
+                changed = true;
+            }
+        }
+
+        // Lines can call each other, so any change can affect every analysis
+        if changed {
+            let analyses = lines
+                .iter()
+                .zip(&settings)
+                .map(|(program, (colour, automaton))| {
+                    let program = program
+                        .clone()
+                        .with_colour(colour.clone())
+                        .with_automaton(*automaton);
+                    analysis::analyse(&program, lines)
+                })
+                .collect();
+            self.text_editor.set_analyses(analyses);
+            self.analysed_settings = settings;
+        }
+    }
+
+    pub fn set_spectrum(&mut self, spectrum: &Spectrum) {
+        self.environment.spectrum.clone_from(spectrum);
+    }
+
+    /// Keep a copy of the last rendered frame for `p` to sample
+    pub fn set_previous_frame(&mut self, frame: &Frame) {
+        self.environment.previous_frame.clone_from(frame);
+    }
+
+    fn open_programs(&mut self, path: &Path) -> anyhow::Result<()> {
+        let mut s = String::new();
+        File::open(path)?.read_to_string(&mut s)?;
+        self.load_programs(s)
+    }
+
+    /// Load a program set, or a plain text file of programs. Nothing changes
+    /// if it can't be loaded
+    pub fn load_programs(&mut self, s: impl AsRef<str>) -> anyhow::Result<()> {
+        self.text_editor
+            .load_program_set(ProgramSet::parse(s.as_ref())?);
+        Ok(())
+    }
+
+    pub fn current_program(&self) -> &Program {
+        &self.current_program
+    }
+
+    /// What programs run against, for running the current one outside the
+    /// renderer
+    pub fn environment(&self) -> &Environment {
+        &self.environment
     }
 
     pub fn draw_input_text(
@@ -267,6 +483,25 @@ impl AppState {
             .draw(d, font, x, y, size, self.primary_colour);
     }
 
+    /// List the words defined by the loaded program set, right aligned to `x`
+    pub fn draw_words(&self, d: &mut RaylibDrawHandle, font: &Font, x: i32, y: i32, size: i32) {
+        let line_height = texteditor::line_height(font, size) as i32;
+        let definitions = self.text_editor.words().definitions();
+        for (i, (name, definition)) in definitions.iter().enumerate() {
+            let text = format!("{name}  {definition}");
+            let width = font.measure_text(&text, size as f32, 0.0).x as i32;
+            draw_text(
+                d,
+                font,
+                text,
+                x - width,
+                y + i as i32 * line_height,
+                size,
+                self.primary_colour,
+            );
+        }
+    }
+
     pub fn draw_play_pause_button(&self, d: &mut RaylibDrawHandle, x: i32, y: i32, width: i32) {
         if self.program_animator.playing() {
             draw_play_button(d, x as f32, y as f32, width as f32, self.primary_colour);
@@ -275,10 +510,39 @@ impl AppState {
         }
     }
 
-    pub fn execute(&mut self, x: i32, y: i32, audio_peak: f32) -> Color {
-        let current = self.text_editor.get_current_line_str().to_owned();
-        let next = self.text_editor.get_next_nonempty().to_owned();
-        self.program_animator
-            .execute(current, next, x, y, self.t + audio_peak * 100.0)
+    /// Returns a function evaluating the colour of any pixel this frame. It
+    /// only borrows the state needed for rendering so that it can be shared
+    /// between the renderer's threads
+    pub fn shader(&self, audio_peak: f32) -> impl Fn(i32, i32) -> Color + Sync + '_ {
+        let program_animator = &self.program_animator;
+        let current_program = &self.current_program;
+        let next_program = &self.next_program;
+        let environment = &self.environment;
+        let t = self.t + audio_peak * 100.0;
+        move |x, y| program_animator.execute(current_program, next_program, x, y, t, environment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejected_program_set_keeps_current_lines() {
+        let mut app_state = AppState::new(&Config::default(), 4, 4);
+        app_state.load_programs("x\n+").unwrap();
+        let lines = app_state.text_editor.lines().to_vec();
+
+        // An unsupported version, an invalid setting, a recursive word and
+        // a word that isn't an uppercase letter
+        for set in [
+            "version = 99\n",
+            "version = 2\n[[lines]]\nprogram = \"x\"\npause_fraction = 1.0\n",
+            "version = 2\n[words]\nA = \"xA\"\n",
+            "version = 2\n[words]\nab = \"x\"\n",
+        ] {
+            assert!(app_state.load_programs(set).is_err());
+            assert_eq!(app_state.text_editor.lines(), lines);
+        }
     }
 }
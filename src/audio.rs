@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use log::info;
+use serde::Deserialize;
+
+use crate::file_audio::FileAudioSource;
+use crate::stdin_audio::StdinAudioSource;
+
+/// Somewhere the visualiser can listen to audio from
+pub trait AudioSource {
+    /// The level of the audio heard since the last call, as the sum over
+    /// channels of the mean absolute sample. `frame_time` is the time since
+    /// the last call, for sources that aren't driven by a real audio device.
+    /// Only call once per frame
+    fn audio_peak(&mut self, frame_time: f32) -> f32;
+
+    /// Fill `samples` with the most recently heard samples mixed down to
+    /// mono, oldest first. Call after `audio_peak` each frame
+    fn recent_samples(&self, samples: &mut [f32]);
+
+    fn sample_rate(&self) -> u32;
+}
+
+/// Always silent, for when no audio input is wanted
+pub struct NullAudioSource;
+
+impl AudioSource for NullAudioSource {
+    fn audio_peak(&mut self, _frame_time: f32) -> f32 {
+        0.0
+    }
+
+    fn recent_samples(&self, samples: &mut [f32]) {
+        samples.fill(0.0);
+    }
+
+    fn sample_rate(&self) -> u32 {
+        44100
+    }
+}
+
+/// The last `capacity` samples heard, mixed down to mono
+pub struct SampleHistory {
+    samples: VecDeque<f32>,
+    capacity: usize,
+}
+
+impl SampleHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::from(vec![0.0; capacity]),
+            capacity,
+        }
+    }
+
+    pub fn push_interleaved(&mut self, samples: &[f32], channels: usize) {
+        for frame in samples.chunks_exact(channels) {
+            if self.samples.len() == self.capacity {
+                self.samples.pop_front();
+            }
+            self.samples
+                .push_back(frame.iter().sum::<f32>() / channels as f32);
+        }
+    }
+
+    pub fn copy_recent(&self, samples: &mut [f32]) {
+        let skip = self.samples.len().saturating_sub(samples.len());
+        let padding = samples.len().saturating_sub(self.samples.len());
+        samples[..padding].fill(0.0);
+        for (sample, value) in samples[padding..]
+            .iter_mut()
+            .zip(self.samples.iter().skip(skip))
+        {
+            *sample = *value;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFormat {
+    #[default]
+    S16le,
+    F32le,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "source", rename_all = "lowercase")]
+pub enum AudioConfig {
+    None,
+    /// Tap all system audio. Only available on macOS
+    CoreAudio,
+    /// Play a WAV or FLAC file, looping at the end
+    File {
+        path: PathBuf,
+    },
+    /// Raw interleaved PCM piped to stdin
+    Stdin {
+        #[serde(default)]
+        format: SampleFormat,
+        #[serde(default = "two")]
+        channels: usize,
+        #[serde(default = "forty_four_thousand_one_hundred")]
+        sample_rate: u32,
+    },
+}
+
+fn two() -> usize {
+    2
+}
+
+fn forty_four_thousand_one_hundred() -> u32 {
+    44100
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        if cfg!(target_os = "macos") {
+            AudioConfig::CoreAudio
+        } else {
+            AudioConfig::None
+        }
+    }
+}
+
+pub fn from_config(config: &AudioConfig) -> anyhow::Result<Box<dyn AudioSource>> {
+    match config {
+        AudioConfig::None => {
+            info!("Audio input disabled");
+            Ok(Box::new(NullAudioSource))
+        }
+        #[cfg(target_os = "macos")]
+        AudioConfig::CoreAudio => Ok(Box::new(crate::mac_audio::VisualiserAudioTap::setup()?)),
+        #[cfg(not(target_os = "macos"))]
+        AudioConfig::CoreAudio => Err(anyhow!("The CoreAudio source is only available on macOS")),
+        AudioConfig::File { path } => Ok(Box::new(FileAudioSource::open(path)?)),
+        AudioConfig::Stdin {
+            format,
+            channels,
+            sample_rate,
+        } => {
+            if *channels == 0 {
+                return Err(anyhow!("Audio from stdin needs at least one channel"));
+            }
+            Ok(Box::new(StdinAudioSource::spawn(
+                *format,
+                *channels,
+                *sample_rate,
+            )))
+        }
+    }
+}
+
+/// Sum over channels of the mean absolute value of interleaved `samples`
+pub fn level(samples: &[f32], channels: usize) -> f32 {
+    let num_frames = samples.len() / channels;
+    if num_frames == 0 {
+        return 0.0;
+    }
+
+    let mut volume_total = 0.0;
+    for channel in 0..channels {
+        let volume_accum: f32 = samples
+            .iter()
+            .skip(channel)
+            .step_by(channels)
+            .take(num_frames)
+            .map(|sample| sample.abs())
+            .sum();
+        volume_total += volume_accum / num_frames as f32;
+    }
+    volume_total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_level_sums_channels() {
+        let samples = [0.5, -0.25, -0.5, 0.25];
+        assert_eq!(level(&samples, 2), 0.75);
+        assert_eq!(level(&samples, 1), 0.375);
+        assert_eq!(level(&[], 2), 0.0);
+    }
+
+    #[test]
+    fn test_history_keeps_most_recent_mono() {
+        let mut history = SampleHistory::new(3);
+        history.push_interleaved(&[1.0, 3.0, 2.0, 4.0, 5.0, 5.0, 6.0, 8.0], 2);
+
+        let mut samples = [0.0; 4];
+        history.copy_recent(&mut samples);
+        assert_eq!(samples, [0.0, 3.0, 5.0, 7.0]);
+
+        let mut samples = [0.0; 2];
+        history.copy_recent(&mut samples);
+        assert_eq!(samples, [5.0, 7.0]);
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: AudioConfig = toml::from_str("source = \"file\"\npath = \"a.flac\"").unwrap();
+        assert!(matches!(config, AudioConfig::File { path } if path == Path::new("a.flac")));
+
+        let config: AudioConfig = toml::from_str("source = \"stdin\"").unwrap();
+        assert!(matches!(
+            config,
+            AudioConfig::Stdin {
+                format: SampleFormat::S16le,
+                channels: 2,
+                sample_rate: 44100
+            }
+        ));
+    }
+}
@@ -0,0 +1,115 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::random::PixelRandom;
+
+/// The state of every cell for automaton lines. States are read from the
+/// last generation while the next one is written, one cell per pixel, so
+/// each pixel evaluated in parallel only writes its own cell.
+#[derive(Debug, Default)]
+pub struct Cells {
+    width: i32,
+    height: i32,
+    seed: u64,
+    states: Vec<f32>,
+    /// The bits of each cell's next state
+    next: Vec<AtomicU32>,
+    /// Whether this frame computes the next generation
+    stepping: bool,
+}
+
+impl Cells {
+    pub fn new(width: i32, height: i32, seed: u64) -> Cells {
+        let len = width as usize * height as usize;
+        let mut cells = Cells {
+            width,
+            height,
+            seed,
+            states: vec![0.0; len],
+            next: (0..len).map(|_| AtomicU32::new(0)).collect(),
+            stepping: false,
+        };
+        cells.reset();
+        cells
+    }
+
+    /// Go back to the first generation, where each cell is 0 or 1 at random
+    pub fn reset(&mut self) {
+        for (i, state) in self.states.iter_mut().enumerate() {
+            let (x, y) = (i as i32 % self.width, i as i32 / self.width);
+            let mut random = PixelRandom::new(self.seed, [x as f32, y as f32, 0.0]);
+            *state = if random.next_f32() < 0.5 { 0.0 } else { 1.0 };
+        }
+        self.stepping = false;
+    }
+
+    /// Start computing the next generation this frame. Cells nobody writes
+    /// keep their state
+    pub fn begin_step(&mut self) {
+        for (next, state) in self.next.iter_mut().zip(&self.states) {
+            *next.get_mut() = state.to_bits();
+        }
+        self.stepping = true;
+    }
+
+    /// Make the generation computed this frame, if any, the current one
+    pub fn finish_step(&mut self) {
+        if !self.stepping {
+            return;
+        }
+        for (state, next) in self.states.iter_mut().zip(&mut self.next) {
+            *state = f32::from_bits(*next.get_mut());
+        }
+        self.stepping = false;
+    }
+
+    fn index(&self, x: f32, y: f32) -> Option<usize> {
+        if self.states.is_empty() || !x.is_finite() || !y.is_finite() {
+            return None;
+        }
+        let x = (x as i64).rem_euclid(self.width as i64) as usize;
+        let y = (y as i64).rem_euclid(self.height as i64) as usize;
+        Some(y * self.width as usize + x)
+    }
+
+    /// State of the cell at (x, y), wrapping around the edges
+    pub fn state(&self, x: f32, y: f32) -> f32 {
+        self.index(x, y).map_or(0.0, |i| self.states[i])
+    }
+
+    /// Sum of the states of the eight cells around (x, y)
+    pub fn neighbours(&self, x: f32, y: f32) -> f32 {
+        let mut sum = 0.0;
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if (dx, dy) != (0, 0) {
+                    sum += self.state(x + dx as f32, y + dy as f32);
+                }
+            }
+        }
+        sum
+    }
+
+    /// Set the next state of the cell at (x, y), if this frame is a step
+    pub fn set_next(&self, x: f32, y: f32, state: f32) {
+        if let (true, Some(i)) = (self.stepping, self.index(x, y)) {
+            self.next[i].store(state.to_bits(), Ordering::Relaxed);
+        }
+    }
+}
+
+impl Clone for Cells {
+    fn clone(&self) -> Self {
+        Cells {
+            width: self.width,
+            height: self.height,
+            seed: self.seed,
+            states: self.states.clone(),
+            next: self
+                .next
+                .iter()
+                .map(|next| AtomicU32::new(next.load(Ordering::Relaxed)))
+                .collect(),
+            stepping: self.stepping,
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::sync::Arc;
+
+use raylib::color::Color;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Colour;
+use crate::programset::LineSettings;
+
+/// How the three values left on the stack, a popped first, become a colour
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ColourModel {
+    /// Hue in degrees, saturation and value
+    #[default]
+    Hsv,
+    /// Red, green and blue
+    Rgb,
+    /// Hue in degrees, saturation and lightness
+    Hsl,
+    /// Lightness, chroma and hue in degrees
+    Oklch,
+    /// a alone picks a colour along the palette, from 0 at the first colour
+    /// to 1 at the last
+    Palette,
+}
+
+impl ColourModel {
+    /// How many of the three values the model uses, counting from a
+    pub fn channels(self) -> usize {
+        match self {
+            ColourModel::Palette => 1,
+            _ => 3,
+        }
+    }
+}
+
+/// What happens to channels outside `[0, 1]`, or palette positions outside
+/// `[0, 1]` in the palette model
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// Channels wrap around past full brightness, and negative ones are
+    /// black. Palette positions wrap back to the start
+    #[default]
+    Wrap,
+    Clamp,
+    /// Values bounce back and forth between 0 and 1
+    Mirror,
+}
+
+impl Overflow {
+    /// A channel in `[0, 1]` as a byte
+    fn byte(self, channel: f32) -> u8 {
+        let value = channel * 255.0;
+        match self {
+            Overflow::Wrap => (value % 256.0) as u8,
+            Overflow::Clamp => value.clamp(0.0, 255.0) as u8,
+            Overflow::Mirror => {
+                let value = value.rem_euclid(510.0);
+                (if value > 255.0 { 510.0 - value } else { value }) as u8
+            }
+        }
+    }
+
+    fn position(self, position: f32) -> f32 {
+        if !position.is_finite() {
+            return 0.0;
+        }
+        match self {
+            Overflow::Wrap => position.rem_euclid(1.0),
+            Overflow::Clamp => position.clamp(0.0, 1.0),
+            Overflow::Mirror => {
+                let position = position.rem_euclid(2.0);
+                if position > 1.0 {
+                    2.0 - position
+                } else {
+                    position
+                }
+            }
+        }
+    }
+}
+
+/// Everything needed to turn a program's output into a colour
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColourOutput {
+    pub model: ColourModel,
+    pub overflow: Overflow,
+    /// Colours of the palette model, evenly spaced
+    pub palette: Arc<[Colour]>,
+    /// Whether a fourth value left on the stack is the opacity
+    pub alpha: bool,
+}
+
+impl Default for ColourOutput {
+    fn default() -> Self {
+        ColourOutput {
+            model: ColourModel::default(),
+            overflow: Overflow::default(),
+            palette: default_palette().into(),
+            alpha: false,
+        }
+    }
+}
+
+/// Black to white
+pub fn default_palette() -> Vec<Colour> {
+    vec![Color::BLACK.into(), Color::WHITE.into()]
+}
+
+impl ColourOutput {
+    /// This output with anything a line sets replaced
+    pub fn for_line(&self, settings: &LineSettings) -> ColourOutput {
+        ColourOutput {
+            model: settings.colour_model.unwrap_or(self.model),
+            overflow: settings.overflow.unwrap_or(self.overflow),
+            palette: match &settings.palette {
+                Some(palette) => palette.as_slice().into(),
+                None => Arc::clone(&self.palette),
+            },
+            alpha: settings.alpha.unwrap_or(self.alpha),
+        }
+    }
+
+    pub fn to_color(&self, a: f32, b: f32, c: f32) -> Color {
+        let [r, g, b] = match self.model {
+            ColourModel::Hsv => hsv(a, b, c),
+            ColourModel::Rgb => [a, b, c],
+            ColourModel::Hsl => hsl(a, b, c),
+            ColourModel::Oklch => oklch(a, b, c),
+            ColourModel::Palette => return self.palette_color(a),
+        };
+        let overflow = self.overflow;
+        Color::new(overflow.byte(r), overflow.byte(g), overflow.byte(b), 255)
+    }
+
+    /// Opacity in `[0, 1]` as a byte
+    pub fn opacity(alpha: f32) -> u8 {
+        (alpha.clamp(0.0, 1.0) * 255.0) as u8
+    }
+
+    fn palette_color(&self, position: f32) -> Color {
+        let Some(last) = self.palette.len().checked_sub(1) else {
+            return Color::BLACK;
+        };
+        let scaled = self.overflow.position(position) * last as f32;
+        let i = (scaled as usize).min(last);
+        let (from, to) = (self.palette[i], self.palette[(i + 1).min(last)]);
+        let f = scaled - i as f32;
+        let mix = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * f) as u8;
+        Color::new(mix(from.r, to.r), mix(from.g, to.g), mix(from.b, to.b), 255)
+    }
+}
+
+// Adapted from https://github.com/raysan5/raylib/blob/16a0b966c3640d679a9bce5c11164945cadd0783/src/rtextures.c#L4959
+fn hsv(h: f32, s: f32, v: f32) -> [f32; 3] {
+    [5.0, 3.0, 1.0].map(|n| {
+        let mut k = (n + h / 60.0) % 6.0;
+        let t = 4.0 - k;
+        k = k.min(t);
+        k = k.min(1.0);
+        k = k.max(0.0);
+        v - v * s * k
+    })
+}
+
+fn hsl(h: f32, s: f32, l: f32) -> [f32; 3] {
+    let a = s * l.min(1.0 - l);
+    [0.0, 8.0, 4.0].map(|n| {
+        let k = (n + h / 30.0).rem_euclid(12.0);
+        l - a * (k - 3.0).min(9.0 - k).clamp(-1.0, 1.0)
+    })
+}
+
+// From https://bottosson.github.io/posts/oklab/
+fn oklch(l: f32, c: f32, h: f32) -> [f32; 3] {
+    let (a, b) = (c * h.to_radians().cos(), c * h.to_radians().sin());
+    let l_ = (l + 0.396_337_78 * a + 0.215_803_76 * b).powi(3);
+    let m_ = (l - 0.105_561_35 * a - 0.063_854_17 * b).powi(3);
+    let s_ = (l - 0.089_484_18 * a - 1.291_485_5 * b).powi(3);
+    [
+        4.076_741_7 * l_ - 3.307_711_6 * m_ + 0.230_969_94 * s_,
+        -1.268_438 * l_ + 2.609_757_4 * m_ - 0.341_319_4 * s_,
+        -0.004_196_086 * l_ - 0.703_418_6 * m_ + 1.707_614_7 * s_,
+    ]
+    .map(srgb_from_linear)
+}
+
+fn srgb_from_linear(x: f32) -> f32 {
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(color: Color) -> [u8; 3] {
+        [color.r, color.g, color.b]
+    }
+
+    #[test]
+    fn test_models_agree_on_pure_colours() {
+        let output = |model| ColourOutput {
+            model,
+            ..Default::default()
+        };
+        assert_eq!(
+            rgb(output(ColourModel::Hsv).to_color(120.0, 1.0, 1.0)),
+            [0, 255, 0]
+        );
+        assert_eq!(
+            rgb(output(ColourModel::Rgb).to_color(0.0, 1.0, 0.0)),
+            [0, 255, 0]
+        );
+        assert_eq!(
+            rgb(output(ColourModel::Hsl).to_color(120.0, 1.0, 0.5)),
+            [0, 255, 0]
+        );
+        // Channels are truncated, so white comes out a hair under 255
+        let white = rgb(output(ColourModel::Oklch).to_color(1.0, 0.0, 0.0));
+        assert!(white.iter().all(|&channel| channel >= 254));
+        assert_eq!(
+            rgb(output(ColourModel::Oklch).to_color(0.0, 0.0, 0.0)),
+            [0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_overflow_modes() {
+        let rgb_with = |overflow, value| {
+            let output = ColourOutput {
+                model: ColourModel::Rgb,
+                overflow,
+                ..Default::default()
+            };
+            output.to_color(value, 0.0, 0.0).r
+        };
+        assert_eq!(rgb_with(Overflow::Wrap, 1.5), 126);
+        assert_eq!(rgb_with(Overflow::Clamp, 1.5), 255);
+        assert_eq!(rgb_with(Overflow::Mirror, 1.5), 127);
+        assert_eq!(rgb_with(Overflow::Mirror, -0.5), 127);
+    }
+
+    #[test]
+    fn test_palette_positions() {
+        let output = |overflow| ColourOutput {
+            model: ColourModel::Palette,
+            overflow,
+            ..Default::default()
+        };
+        let grey = |overflow, position| output(overflow).to_color(position, 0.0, 0.0).r;
+        assert_eq!(grey(Overflow::Clamp, 0.5), 127);
+        assert_eq!(grey(Overflow::Clamp, 2.0), 255);
+        assert_eq!(grey(Overflow::Wrap, 1.25), 63);
+        assert_eq!(grey(Overflow::Mirror, 1.25), 191);
+        assert_eq!(grey(Overflow::Wrap, f32::NAN), 0);
+    }
+}
@@ -1,14 +1,19 @@
 use raylib::core::color as rl_color;
 use std::{fs::File, io::Read, path::Path};
 
+use anyhow::anyhow;
 use log::info;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+use crate::audio::AudioConfig;
+use crate::colour::{self, ColourModel, ColourOutput, Overflow};
+use crate::random::RandomMode;
 
 fn two_five_five() -> u8 {
     255
 }
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Colour {
     pub r: u8,
@@ -46,7 +51,7 @@ impl Default for Colour {
     }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 #[serde(default)]
 pub struct Config {
     pub video_frames: u32,
@@ -56,6 +61,24 @@ pub struct Config {
     pub cycle_time: f32,
     pub pause_fraction: f32,
     pub primary_colour: Colour,
+    /// Makes the `r` operator and the sequencer reproducible
+    pub seed: Option<u64>,
+    /// Defaults to `hash` when a seed is given and `thread` otherwise
+    pub random: Option<RandomMode>,
+    /// How programs' output becomes a colour, unless a line says otherwise
+    pub colour_model: ColourModel,
+    pub overflow: Overflow,
+    pub palette: Vec<Colour>,
+    /// Read a fourth value left on the stack as opacity
+    pub alpha: bool,
+    /// What transparent pixels are drawn over
+    pub background: Colour,
+    /// Generations per second of automaton lines
+    pub step_rate: f32,
+    /// Have the sequencer pass over lines that are a flat colour or pure
+    /// noise
+    pub skip_degenerate: bool,
+    pub audio: AudioConfig,
 }
 
 impl Default for Config {
@@ -68,11 +91,37 @@ impl Default for Config {
             cycle_time: 20.0,
             pause_fraction: 0.5,
             primary_colour: Default::default(),
+            seed: None,
+            random: None,
+            colour_model: Default::default(),
+            overflow: Default::default(),
+            palette: colour::default_palette(),
+            alpha: false,
+            background: rl_color::Color::BLACK.into(),
+            step_rate: 10.0,
+            skip_degenerate: false,
+            audio: Default::default(),
         }
     }
 }
 
 impl Config {
+    pub fn colour_output(&self) -> ColourOutput {
+        ColourOutput {
+            model: self.colour_model,
+            overflow: self.overflow,
+            palette: self.palette.as_slice().into(),
+            alpha: self.alpha,
+        }
+    }
+
+    pub fn random_mode(&self) -> RandomMode {
+        self.random.unwrap_or(match self.seed {
+            Some(_) => RandomMode::Hash,
+            None => RandomMode::Thread,
+        })
+    }
+
     pub fn from_file<P: AsRef<Path>>(filepath: P) -> anyhow::Result<Config> {
         if !filepath.as_ref().try_exists()? {
             info!("Loaded default config");
@@ -83,6 +132,9 @@ impl Config {
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
         let config: Config = toml::from_str(&contents)?;
+        if config.step_rate <= 0.0 {
+            return Err(anyhow!("step_rate must be greater than 0"));
+        }
         info!(
             "Loaded config from file {}",
             filepath.as_ref().to_str().unwrap()
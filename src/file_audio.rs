@@ -0,0 +1,118 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use log::info;
+
+use crate::audio;
+use crate::audio::AudioSource;
+
+/// Plays back a decoded WAV or FLAC file, advancing by the frame time given
+/// on each call so that offline renders stay in step with the audio
+pub struct FileAudioSource {
+    samples: Vec<f32>,
+    channels: usize,
+    sample_rate: u32,
+    t: f64,
+    position: usize,
+}
+
+impl FileAudioSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+
+        let (samples, channels, sample_rate) = match extension.as_deref() {
+            Some("wav") => decode_wav(path)?,
+            Some("flac") => decode_flac(path)?,
+            _ => return Err(anyhow!("Audio file must be a .wav or .flac file")),
+        };
+
+        if samples.is_empty() {
+            return Err(anyhow!("Audio file {} is empty", path.display()));
+        }
+
+        info!(
+            "Loaded audio file {} ({} channels, {} Hz)",
+            path.display(),
+            channels,
+            sample_rate
+        );
+
+        Ok(Self {
+            samples,
+            channels,
+            sample_rate,
+            t: 0.0,
+            position: 0,
+        })
+    }
+
+    fn num_frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+}
+
+impl AudioSource for FileAudioSource {
+    fn audio_peak(&mut self, frame_time: f32) -> f32 {
+        self.t += frame_time as f64;
+        let end = (self.t * self.sample_rate as f64) as usize;
+        let num_frames = self.num_frames();
+
+        let mut window = Vec::with_capacity((end - self.position) * self.channels);
+        for frame in self.position..end {
+            let start = (frame % num_frames) * self.channels;
+            window.extend_from_slice(&self.samples[start..start + self.channels]);
+        }
+        self.position = end;
+
+        audio::level(&window, self.channels)
+    }
+
+    fn recent_samples(&self, samples: &mut [f32]) {
+        let num_frames = self.num_frames();
+        for (i, sample) in samples.iter_mut().rev().enumerate() {
+            // Silence before the start of the file
+            let Some(frame) = self.position.checked_sub(i + 1) else {
+                *sample = 0.0;
+                continue;
+            };
+            let start = (frame % num_frames) * self.channels;
+            let frame = &self.samples[start..start + self.channels];
+            *sample = frame.iter().sum::<f32>() / self.channels as f32;
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+fn decode_wav(path: &Path) -> anyhow::Result<(Vec<f32>, usize, u32)> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let max = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 / max))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    Ok((samples, spec.channels as usize, spec.sample_rate))
+}
+
+fn decode_flac(path: &Path) -> anyhow::Result<(Vec<f32>, usize, u32)> {
+    let mut reader = claxon::FlacReader::open(path)?;
+    let info = reader.streaminfo();
+    let max = (1i64 << (info.bits_per_sample - 1)) as f32;
+    let samples = reader
+        .samples()
+        .map(|sample| sample.map(|sample| sample as f32 / max))
+        .collect::<Result<_, _>>()?;
+    Ok((samples, info.channels as usize, info.sample_rate))
+}
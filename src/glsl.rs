@@ -0,0 +1,1052 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use log::info;
+
+use crate::colour::{ColourModel, ColourOutput, Overflow};
+use crate::config::Config;
+use crate::program::{
+    AUDIO_SCALE, EQUALITY_EPSILON, Environment, INSTRUCTION_BUDGET, Instruction, MAX_CALL_DEPTH,
+    Program, Stack, line_index, loop_count, matching_loop_end,
+};
+use crate::programset::ProgramSet;
+
+#[derive(clap::Args)]
+pub struct ExportArgs {
+    /// Program line to export, e.g. '%c^/m|xe'
+    program: String,
+    /// Shader file to write. Prints to stdout when not given
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Program set to take any words, and lines called with `#`, from
+    #[arg(short, long)]
+    set: Option<PathBuf>,
+    /// Colour model to use instead of the one in config.toml
+    #[arg(long, value_enum)]
+    colour_model: Option<ColourModel>,
+    /// Overflow to use instead of the one in config.toml
+    #[arg(long, value_enum)]
+    overflow: Option<Overflow>,
+}
+
+/// Inputs the reference check evaluates each program at
+const SAMPLE_XS: [f32; 5] = [0.0, 1.0, 17.0, 80.0, 159.0];
+const SAMPLE_YS: [f32; 4] = [0.0, 3.0, 60.0, 119.0];
+const SAMPLE_TS: [f32; 4] = [0.0, 0.5, 12.25, 1000.0];
+
+const INPUT_NAMES: [&str; 3] = ["x", "y", "t"];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    /// x, y or t
+    Input(usize),
+    Constant(f32),
+    /// An operator applied to the values it popped, in the order they were
+    /// popped
+    Apply(Instruction, Vec<usize>),
+    /// Hue, saturation or value of the previous frame at (x, y)
+    PreviousFrame {
+        channel: usize,
+        x: usize,
+        y: usize,
+    },
+}
+
+/// A program with its stack resolved at compile time. Every value the VM
+/// would compute becomes a node, and every stack operation and underflow
+/// becomes a reference to an earlier node.
+#[derive(Debug)]
+pub struct SymbolicProgram {
+    nodes: Vec<Node>,
+    /// The h, s and v nodes left at the end of the program
+    outputs: [usize; 3],
+    /// A fourth node left on the stack, used as opacity when the colour
+    /// output has alpha
+    alpha: Option<usize>,
+}
+
+/// The stack of a program being traced. Holds node indices rather than
+/// values, and like the ring buffer in the VM, an underflowing pop yields
+/// x, y and t in turn.
+struct SymbolicStack<'a> {
+    nodes: Vec<Node>,
+    stack: Vec<usize>,
+    ring_position: usize,
+    /// Lines that `#` can call, which are inlined
+    lines: &'a [Program],
+    call_depth: usize,
+    /// Instructions left, counted exactly as the VM does, since loops are
+    /// unrolled
+    budget: u32,
+}
+
+impl<'a> SymbolicStack<'a> {
+    fn new(lines: &'a [Program]) -> SymbolicStack<'a> {
+        SymbolicStack {
+            // Nodes 0, 1 and 2 are x, y and t
+            nodes: (0..3).map(Node::Input).collect(),
+            stack: vec![2, 1, 0],
+            ring_position: 0,
+            lines,
+            call_depth: 0,
+            budget: INSTRUCTION_BUDGET,
+        }
+    }
+
+    fn run(&mut self, instructions: &[Instruction]) -> anyhow::Result<()> {
+        let mut loops: Vec<(usize, u32)> = Vec::new();
+        let mut pc = 0;
+        while pc < instructions.len() && self.budget > 0 {
+            self.budget -= 1;
+            match instructions[pc] {
+                Instruction::LoopStart => {
+                    let node = self.pop();
+                    let count = match self.nodes[node] {
+                        Node::Constant(count) => loop_count(count),
+                        _ => return Err(anyhow!("Only loops with a fixed count can be exported")),
+                    };
+                    if count == 0 {
+                        pc = matching_loop_end(instructions, pc);
+                    } else {
+                        loops.push((pc + 1, count));
+                    }
+                }
+                Instruction::LoopEnd => {
+                    if let Some((start, remaining)) = loops.last_mut() {
+                        *remaining -= 1;
+                        if *remaining > 0 {
+                            pc = *start;
+                            continue;
+                        }
+                        loops.pop();
+                    }
+                }
+                instruction => self.execute(instruction)?,
+            }
+            pc += 1;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, node: usize) {
+        self.stack.push(node);
+    }
+
+    fn pop(&mut self) -> usize {
+        match self.stack.pop() {
+            Some(node) => node,
+            None => {
+                let node = self.ring_position;
+                self.ring_position = (self.ring_position + 1) % 3;
+                node
+            }
+        }
+    }
+
+    fn add(&mut self, node: Node) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    fn apply(&mut self, instruction: Instruction, arity: usize) {
+        let args = (0..arity).map(|_| self.pop()).collect();
+        let node = self.add(Node::Apply(instruction, args));
+        self.push(node);
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> anyhow::Result<()> {
+        match instruction {
+            Instruction::Literal(value) => {
+                // GLSL has no way to write infinity
+                if !value.is_finite() {
+                    return Err(anyhow!("Numbers too large for an f32 can't be exported"));
+                }
+                let node = self.add(Node::Constant(value));
+                self.push(node);
+            }
+            Instruction::Swap => {
+                let (val1, val2) = (self.pop(), self.pop());
+                self.push(val1);
+                self.push(val2);
+            }
+            Instruction::Duplicate => {
+                let val = self.pop();
+                self.push(val);
+                self.push(val);
+            }
+            Instruction::Pop => {
+                self.pop();
+            }
+            Instruction::Tuck => {
+                let (val1, val2) = (self.pop(), self.pop());
+                self.push(val1);
+                self.push(val2);
+                self.push(val1);
+            }
+            Instruction::DuplicateUnder => {
+                let (val1, val2) = (self.pop(), self.pop());
+                self.push(val2);
+                self.push(val2);
+                self.push(val1);
+            }
+            Instruction::LockBrightness => {
+                let (val, val2) = (self.pop(), self.pop());
+                let one = self.add(Node::Constant(1.0));
+                self.push(one);
+                self.push(val2);
+                self.push(val);
+            }
+            Instruction::PreviousFrame => {
+                let (x, y) = (self.pop(), self.pop());
+                for channel in [2, 1, 0] {
+                    let node = self.add(Node::PreviousFrame { channel, x, y });
+                    self.push(node);
+                }
+            }
+            Instruction::Negate
+            | Instruction::Frequency
+            | Instruction::Sqrt
+            | Instruction::Abs
+            | Instruction::Floor
+            | Instruction::Fract
+            | Instruction::Blur
+            | Instruction::Laplacian
+            | Instruction::Sobel => self.apply(instruction, 1),
+            Instruction::Bass | Instruction::Mid | Instruction::Treble => {
+                self.apply(instruction, 0)
+            }
+            Instruction::Store | Instruction::Load => {
+                return Err(anyhow!(
+                    "Registers can't be exported, since shaders keep nothing between frames"
+                ));
+            }
+            Instruction::Cell | Instruction::Neighbours => {
+                return Err(anyhow!("Automaton cells can't be exported"));
+            }
+            Instruction::Call => {
+                let node = self.pop();
+                let index = match self.nodes[node] {
+                    Node::Constant(index) => index,
+                    _ => return Err(anyhow!("Only calls to a fixed line can be exported")),
+                };
+                let lines = self.lines;
+                if self.call_depth < MAX_CALL_DEPTH
+                    && let Some(i) = line_index(index, lines.len())
+                {
+                    self.call_depth += 1;
+                    self.run(lines[i].instructions())?;
+                    self.call_depth -= 1;
+                }
+            }
+            Instruction::Select
+            | Instruction::ValueNoise3
+            | Instruction::PerlinNoise3
+            | Instruction::SimplexNoise3 => self.apply(instruction, 3),
+            Instruction::Fbm => self.apply(instruction, 4),
+            _ => self.apply(instruction, 2),
+        }
+        Ok(())
+    }
+}
+
+impl SymbolicProgram {
+    /// Resolve the stack of `program`, inlining any of `lines` it calls
+    pub fn trace(program: &Program, lines: &[Program]) -> anyhow::Result<SymbolicProgram> {
+        let mut stack = SymbolicStack::new(lines);
+        stack.run(program.instructions())?;
+        let outputs = [stack.pop(), stack.pop(), stack.pop()];
+        let alpha = stack.stack.pop();
+        Ok(SymbolicProgram {
+            nodes: stack.nodes,
+            outputs,
+            alpha,
+        })
+    }
+
+    /// Evaluate every node in order on the CPU, returning h, s and v. Each
+    /// operator is run by the VM itself, so this checks the stack has been
+    /// resolved correctly, and random numbers are drawn in the same order.
+    pub fn evaluate(&self, initial_values: [f32; 3], environment: &Environment) -> [f32; 3] {
+        let mut stack = Stack::new(initial_values, environment);
+        let mut values = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let value = match node {
+                Node::Input(i) => initial_values[*i],
+                Node::Constant(value) => *value,
+                Node::Apply(instruction, args) => {
+                    for &arg in args.iter().rev() {
+                        stack.push(values[arg]);
+                    }
+                    stack.execute(*instruction);
+                    stack.pop()
+                }
+                Node::PreviousFrame { channel, x, y } => {
+                    stack.push(values[*y]);
+                    stack.push(values[*x]);
+                    stack.execute(Instruction::PreviousFrame);
+                    [stack.pop(), stack.pop(), stack.pop()][*channel]
+                }
+            };
+            values.push(value);
+        }
+        self.outputs.map(|node| values[node])
+    }
+
+    /// Nodes the outputs depend on, including the opacity if `alpha`
+    fn live_nodes(&self, alpha: Option<usize>) -> Vec<bool> {
+        let mut live = vec![false; self.nodes.len()];
+        let mut pending = self.outputs.to_vec();
+        pending.extend(alpha);
+        while let Some(node) = pending.pop() {
+            if live[node] {
+                continue;
+            }
+            live[node] = true;
+            match &self.nodes[node] {
+                Node::Apply(_, args) => pending.extend(args),
+                Node::PreviousFrame { x, y, .. } => pending.extend([x, y]),
+                _ => {}
+            }
+        }
+        live
+    }
+
+    fn uses(&self, predicate: impl Fn(Instruction) -> bool) -> bool {
+        self.nodes
+            .iter()
+            .any(|node| matches!(node, Node::Apply(instruction, _) if predicate(*instruction)))
+    }
+
+    fn operand(&self, node: usize) -> String {
+        match &self.nodes[node] {
+            Node::Input(i) => INPUT_NAMES[*i].to_string(),
+            Node::Constant(value) => format!("{value:?}"),
+            Node::Apply(..) | Node::PreviousFrame { .. } => format!("v{node}"),
+        }
+    }
+
+    /// Emit a fragment shader computing the same colour as the program.
+    /// `t` is a uniform, while `x` and `y` come from the fragment's position
+    /// divided by the `scale` uniform, with y = 0 at the top.
+    pub fn to_glsl(&self, source: &str, colour: &ColourOutput) -> String {
+        let uses_random = self.uses(|i| i == Instruction::Random);
+        let uses_noise = self.uses(|i| {
+            matches!(
+                i,
+                Instruction::ValueNoise2
+                    | Instruction::ValueNoise3
+                    | Instruction::PerlinNoise2
+                    | Instruction::PerlinNoise3
+                    | Instruction::SimplexNoise2
+                    | Instruction::SimplexNoise3
+                    | Instruction::Fbm
+            )
+        });
+        let uses_spectrum = self.uses(|i| i == Instruction::Frequency);
+        let uses_previous_frame = self
+            .nodes
+            .iter()
+            .any(|node| matches!(node, Node::PreviousFrame { .. }));
+        let uses_convolution = self.uses(|i| {
+            matches!(
+                i,
+                Instruction::Blur | Instruction::Laplacian | Instruction::Sobel
+            )
+        });
+        let uses_bands = self.uses(|i| {
+            matches!(
+                i,
+                Instruction::Bass | Instruction::Mid | Instruction::Treble
+            )
+        });
+
+        let mut glsl = String::new();
+        let _ = writeln!(glsl, "// Generated by visualiserbox from: {source}");
+        glsl.push_str(HEADER);
+        if uses_spectrum {
+            glsl.push_str("uniform sampler2D spectrum;\n");
+        }
+        if uses_bands {
+            glsl.push_str("uniform float bass;\nuniform float mid;\nuniform float treble;\n");
+        }
+        if uses_previous_frame || uses_convolution {
+            glsl.push_str("uniform sampler2D previous_frame;\n");
+        }
+        glsl.push('\n');
+        glsl.push_str(HELPERS);
+        glsl.push_str(&colour_glsl(colour));
+        if uses_random {
+            glsl.push_str(RANDOM);
+        }
+        if uses_noise {
+            glsl.push_str(NOISE);
+        }
+        if uses_spectrum {
+            glsl.push_str(SPECTRUM);
+        }
+        if uses_previous_frame {
+            glsl.push_str(PREVIOUS_FRAME);
+        }
+        if uses_convolution {
+            glsl.push_str(CONVOLUTION);
+        }
+
+        glsl.push_str("vec4 program(float x, float y, float t) {\n");
+        let alpha = self.alpha.filter(|_| colour.alpha);
+        let live = self.live_nodes(alpha);
+        let mut draws = 0;
+        for (i, node) in self.nodes.iter().enumerate() {
+            let expression = match node {
+                Node::Apply(instruction, args) => {
+                    // Number every draw, live or not, so each `r` gets its own value
+                    let draw = draws;
+                    if *instruction == Instruction::Random {
+                        draws += 1;
+                    }
+                    let args: Vec<String> = args.iter().map(|&arg| self.operand(arg)).collect();
+                    expression(*instruction, &args, draw)
+                }
+                Node::PreviousFrame { channel, x, y } => format!(
+                    "previous_hsv({}, {})[{channel}]",
+                    self.operand(*x),
+                    self.operand(*y)
+                ),
+                _ => continue,
+            };
+            if live[i] {
+                let _ = writeln!(glsl, "    float v{i} = {expression};");
+            }
+        }
+        let [h, s, v] = self.outputs.map(|node| self.operand(node));
+        match alpha {
+            Some(alpha) => {
+                let alpha = self.operand(alpha);
+                let _ = writeln!(
+                    glsl,
+                    "    return vec4(colour({h}, {s}, {v}).rgb, clamp({alpha}, 0.0, 1.0));\n}}\n"
+                );
+            }
+            None => {
+                let _ = writeln!(glsl, "    return colour({h}, {s}, {v});\n}}\n");
+            }
+        }
+        glsl.push_str(MAIN);
+        glsl
+    }
+}
+
+fn expression(instruction: Instruction, args: &[String], draw: usize) -> String {
+    let arg = |i: usize| args[i].as_str();
+    match instruction {
+        Instruction::Xor => format!("float(int({}) ^ int({}))", arg(0), arg(1)),
+        Instruction::And => format!("float(int({}) & int({}))", arg(0), arg(1)),
+        Instruction::Or => format!("float(int({}) | int({}))", arg(0), arg(1)),
+        Instruction::Add => format!("{} + {}", arg(0), arg(1)),
+        Instruction::Subtract => format!("{} - {}", arg(0), arg(1)),
+        Instruction::Multiply => format!("{} * {}", arg(0), arg(1)),
+        Instruction::Divide => format!("safe_div({}, {})", arg(0), arg(1)),
+        Instruction::Log => format!("{} * log(max({}, 1.0))", arg(0), arg(1)),
+        Instruction::Exp => format!("{} * exp({})", arg(0), arg(1)),
+        Instruction::Cos => format!("{} * cos({})", arg(0), arg(1)),
+        Instruction::Sin => format!("{} * sin({})", arg(0), arg(1)),
+        Instruction::Sqrt => format!("sqrt(max({}, 0.0))", arg(0)),
+        Instruction::Abs => format!("abs({})", arg(0)),
+        Instruction::Floor => format!("floor({})", arg(0)),
+        Instruction::Fract => format!("safe_fract({})", arg(0)),
+        Instruction::Atan2 => format!("atan({}, {})", arg(0), arg(1)),
+        Instruction::Pow => format!("pow(abs({}), {})", arg(0), arg(1)),
+        Instruction::Min => format!("min({}, {})", arg(0), arg(1)),
+        Instruction::Max => format!("max({}, {})", arg(0), arg(1)),
+        Instruction::Modulo => format!("fmod({}, {})", arg(0), arg(1)),
+        Instruction::Random => {
+            format!("{} * random(x, y, t, {draw}.0) + {}", arg(1), arg(0))
+        }
+        Instruction::Negate => format!("-{}", arg(0)),
+        Instruction::LessThan => format!("float({} < {})", arg(0), arg(1)),
+        Instruction::Equal => format!("float(abs({} - {}) < {EQUALITY_EPSILON:?})", arg(0), arg(1)),
+        Instruction::Select => format!("{} != 0.0 ? {} : {}", arg(0), arg(2), arg(1)),
+        Instruction::ValueNoise2 => format!("value_noise3({}, {}, 0.0)", arg(0), arg(1)),
+        Instruction::ValueNoise3 => format!("value_noise3({}, {}, {})", arg(0), arg(1), arg(2)),
+        Instruction::PerlinNoise2 => format!("perlin_noise2({}, {})", arg(0), arg(1)),
+        Instruction::PerlinNoise3 => format!(
+            "unit_noise(perlin_noise3({}, {}, {}))",
+            arg(0),
+            arg(1),
+            arg(2)
+        ),
+        Instruction::SimplexNoise2 => format!("simplex_noise2({}, {})", arg(0), arg(1)),
+        Instruction::SimplexNoise3 => {
+            format!("simplex_noise3({}, {}, {})", arg(0), arg(1), arg(2))
+        }
+        Instruction::Fbm => format!("fbm({}, {}, {}, {})", arg(1), arg(2), arg(3), arg(0)),
+        Instruction::Frequency => format!("magnitude({}) * {AUDIO_SCALE:?}", arg(0)),
+        Instruction::Bass => format!("bass * {AUDIO_SCALE:?}"),
+        Instruction::Mid => format!("mid * {AUDIO_SCALE:?}"),
+        Instruction::Treble => format!("treble * {AUDIO_SCALE:?}"),
+        Instruction::Blur => format!("blur({}, x, y)", arg(0)),
+        Instruction::Laplacian => format!("laplacian({}, x, y)", arg(0)),
+        Instruction::Sobel => format!("sobel({}, x, y)", arg(0)),
+        Instruction::Literal(_)
+        | Instruction::LoopStart
+        | Instruction::LoopEnd
+        | Instruction::Swap
+        | Instruction::Duplicate
+        | Instruction::Pop
+        | Instruction::Tuck
+        | Instruction::DuplicateUnder
+        | Instruction::LockBrightness
+        | Instruction::Store
+        | Instruction::Load
+        | Instruction::Cell
+        | Instruction::Neighbours
+        | Instruction::PreviousFrame
+        | Instruction::Call => {
+            unreachable!("stack operations are resolved when tracing")
+        }
+    }
+}
+
+const HEADER: &str = "#version 330
+
+uniform float t;
+uniform float scale;
+uniform vec2 resolution;
+";
+
+const HELPERS: &str = "out vec4 fragColor;
+
+// Remainder with the sign of the dividend, like % in Rust
+float fmod(float a, float b) {
+    return a - b * trunc(a / b);
+}
+
+float safe_div(float a, float b) {
+    return a / (b == 0.0 ? 1.0 : b);
+}
+
+float safe_fract(float a) {
+    return isinf(a) || isnan(a) ? 0.0 : fract(a);
+}
+
+";
+
+// Each colour model as a function from the program's output to unclamped
+// RGB, mirroring colour.rs
+const HSV: &str = "float hsv_channel(float n, float h, float s, float v) {
+    float k = fmod(n + h / 60.0, 6.0);
+    k = clamp(min(k, 4.0 - k), 0.0, 1.0);
+    return v - v * s * k;
+}
+
+vec3 model_rgb(float h, float s, float v) {
+    return vec3(hsv_channel(5.0, h, s, v), hsv_channel(3.0, h, s, v), hsv_channel(1.0, h, s, v));
+}
+
+";
+
+const RGB: &str = "vec3 model_rgb(float r, float g, float b) {
+    return vec3(r, g, b);
+}
+
+";
+
+const HSL: &str = "float hsl_channel(float n, float h, float s, float l) {
+    float k = mod(n + h / 30.0, 12.0);
+    return l - s * min(l, 1.0 - l) * clamp(min(k - 3.0, 9.0 - k), -1.0, 1.0);
+}
+
+vec3 model_rgb(float h, float s, float l) {
+    return vec3(hsl_channel(0.0, h, s, l), hsl_channel(8.0, h, s, l), hsl_channel(4.0, h, s, l));
+}
+
+";
+
+const OKLCH: &str = "float srgb_from_linear(float x) {
+    return x <= 0.0031308 ? 12.92 * x : 1.055 * pow(x, 1.0 / 2.4) - 0.055;
+}
+
+vec3 model_rgb(float l, float c, float h) {
+    float a = c * cos(radians(h));
+    float b = c * sin(radians(h));
+    vec3 lms = vec3(
+        l + 0.39633778 * a + 0.21580376 * b,
+        l - 0.10556135 * a - 0.06385417 * b,
+        l - 0.08948418 * a - 1.2914855 * b);
+    lms = lms * lms * lms;
+    vec3 rgb = vec3(
+        dot(lms, vec3(4.0767417, -3.3077116, 0.23096994)),
+        dot(lms, vec3(-1.268438, 2.6097574, -0.3413194)),
+        dot(lms, vec3(-0.004196086, -0.7034186, 1.7076147)));
+    return vec3(srgb_from_linear(rgb.r), srgb_from_linear(rgb.g), srgb_from_linear(rgb.b));
+}
+
+";
+
+const CHANNELS: &str = "vec4 colour(float a, float b, float c) {
+    vec3 rgb = model_rgb(a, b, c);
+    return vec4(channel(rgb.r), channel(rgb.g), channel(rgb.b), 1.0);
+}
+
+";
+
+const PALETTE: &str = "vec4 colour(float a, float b, float c) {
+    int last = palette.length() - 1;
+    float scaled = palette_position(a) * float(last);
+    int i = min(int(scaled), last);
+    vec3 rgb = mix(palette[i], palette[min(i + 1, last)], scaled - float(i));
+    return vec4(trunc(rgb) / 255.0, 1.0);
+}
+
+";
+
+/// The `colour` function the shader's output goes through
+fn colour_glsl(colour: &ColourOutput) -> String {
+    let model = match colour.model {
+        ColourModel::Hsv => HSV,
+        ColourModel::Rgb => RGB,
+        ColourModel::Hsl => HSL,
+        ColourModel::Oklch => OKLCH,
+        ColourModel::Palette => return palette_glsl(colour),
+    };
+    let channel = match colour.overflow {
+        Overflow::Wrap => "clamp(trunc(fmod(c * 255.0, 256.0)), 0.0, 255.0)",
+        Overflow::Clamp => "trunc(clamp(c * 255.0, 0.0, 255.0))",
+        Overflow::Mirror => "trunc(255.0 - abs(255.0 - mod(c * 255.0, 510.0)))",
+    };
+    format!("{model}float channel(float c) {{\n    return {channel} / 255.0;\n}}\n\n{CHANNELS}")
+}
+
+fn palette_glsl(colour: &ColourOutput) -> String {
+    if colour.palette.is_empty() {
+        return "vec4 colour(float a, float b, float c) {\n    return vec4(0.0, 0.0, 0.0, 1.0);\n}\n\n"
+            .to_string();
+    }
+    let colours: Vec<String> = colour
+        .palette
+        .iter()
+        .map(|c| format!("vec3({:?}, {:?}, {:?})", c.r as f32, c.g as f32, c.b as f32))
+        .collect();
+    let position = match colour.overflow {
+        Overflow::Wrap => "fract(p)",
+        Overflow::Clamp => "clamp(p, 0.0, 1.0)",
+        Overflow::Mirror => "1.0 - abs(1.0 - mod(p, 2.0))",
+    };
+    format!(
+        "const vec3 palette[{}] = vec3[]({});\n\nfloat palette_position(float p) {{\n    return isinf(p) || isnan(p) ? 0.0 : {position};\n}}\n\n{PALETTE}",
+        colours.len(),
+        colours.join(", ")
+    )
+}
+
+const RANDOM: &str = "float random(float x, float y, float t, float draw) {
+    return fract(sin(dot(vec4(x, y, t, draw), vec4(12.9898, 78.233, 37.719, 4.581))) * 43758.5453);
+}
+
+";
+
+// Mirrors noise.rs
+const NOISE: &str = "float finite_or_zero(float v) {
+    return isinf(v) || isnan(v) ? 0.0 : v;
+}
+
+uint noise_hash(uint h) {
+    h ^= h >> 16u;
+    h *= 0x7feb352du;
+    h ^= h >> 15u;
+    h *= 0x846ca68bu;
+    return h ^ (h >> 16u);
+}
+
+uint lattice(ivec3 p) {
+    return noise_hash(uint(p.x) ^ noise_hash(uint(p.y) ^ noise_hash(uint(p.z))));
+}
+
+float lattice_value(ivec3 p) {
+    return float(lattice(p) >> 8u) / 16777216.0;
+}
+
+float flip(uint h, uint bit, float v) {
+    return (h & bit) == 0u ? v : -v;
+}
+
+float gradient2(uint h, vec2 p) {
+    uint g = h & 7u;
+    if (g < 4u) {
+        return flip(h, 1u, p.x) + flip(h, 2u, p.y);
+    }
+    return flip(h, 1u, g < 6u ? p.x : p.y);
+}
+
+float gradient3(uint h, vec3 p) {
+    h &= 15u;
+    float u = h < 8u ? p.x : p.y;
+    float v = h < 4u ? p.y : (h == 12u || h == 14u ? p.x : p.z);
+    return flip(h, 1u, u) + flip(h, 2u, v);
+}
+
+vec3 fade(vec3 t) {
+    return t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+}
+
+float unit_noise(float n) {
+    return clamp(0.5 + 0.5 * n, 0.0, 1.0);
+}
+
+float value_noise3(float x, float y, float z) {
+    vec3 p = vec3(finite_or_zero(x), finite_or_zero(y), finite_or_zero(z));
+    ivec3 i = ivec3(floor(p));
+    vec3 u = fade(p - floor(p));
+    float faces[2];
+    for (int dz = 0; dz < 2; dz++) {
+        faces[dz] = mix(
+            mix(lattice_value(i + ivec3(0, 0, dz)), lattice_value(i + ivec3(1, 0, dz)), u.x),
+            mix(lattice_value(i + ivec3(0, 1, dz)), lattice_value(i + ivec3(1, 1, dz)), u.x),
+            u.y);
+    }
+    return mix(faces[0], faces[1], u.z);
+}
+
+float perlin_noise2(float x, float y) {
+    vec2 p = vec2(finite_or_zero(x), finite_or_zero(y));
+    ivec3 i = ivec3(ivec2(floor(p)), 0);
+    vec2 f = p - floor(p);
+    vec2 u = fade(vec3(f, 0.0)).xy;
+    return unit_noise(mix(
+        mix(gradient2(lattice(i), f), gradient2(lattice(i + ivec3(1, 0, 0)), f - vec2(1.0, 0.0)), u.x),
+        mix(gradient2(lattice(i + ivec3(0, 1, 0)), f - vec2(0.0, 1.0)), gradient2(lattice(i + ivec3(1, 1, 0)), f - vec2(1.0)), u.x),
+        u.y));
+}
+
+// In roughly [-1, 1]
+float perlin_noise3(float x, float y, float z) {
+    vec3 p = vec3(finite_or_zero(x), finite_or_zero(y), finite_or_zero(z));
+    ivec3 i = ivec3(floor(p));
+    vec3 f = p - floor(p);
+    vec3 u = fade(f);
+    float faces[2];
+    for (int dz = 0; dz < 2; dz++) {
+        vec3 d = vec3(0.0, 0.0, float(dz));
+        faces[dz] = mix(
+            mix(gradient3(lattice(i + ivec3(0, 0, dz)), f - d),
+                gradient3(lattice(i + ivec3(1, 0, dz)), f - d - vec3(1.0, 0.0, 0.0)), u.x),
+            mix(gradient3(lattice(i + ivec3(0, 1, dz)), f - d - vec3(0.0, 1.0, 0.0)),
+                gradient3(lattice(i + ivec3(1, 1, dz)), f - d - vec3(1.0, 1.0, 0.0)), u.x),
+            u.y);
+    }
+    return mix(faces[0], faces[1], u.z);
+}
+
+float simplex_corner2(ivec2 i, vec2 d) {
+    float falloff = 0.5 - dot(d, d);
+    return falloff <= 0.0 ? 0.0 : pow(falloff, 4.0) * gradient2(lattice(ivec3(i, 0)), d);
+}
+
+float simplex_noise2(float x, float y) {
+    const float F2 = 0.36602542;
+    const float G2 = 0.21132487;
+    vec2 p = vec2(finite_or_zero(x), finite_or_zero(y));
+    vec2 i = floor(p + (p.x + p.y) * F2);
+    vec2 p0 = p - (i - (i.x + i.y) * G2);
+    ivec2 i1 = p0.x > p0.y ? ivec2(1, 0) : ivec2(0, 1);
+    return unit_noise(70.0 * (simplex_corner2(ivec2(i), p0)
+        + simplex_corner2(ivec2(i) + i1, p0 - vec2(i1) + G2)
+        + simplex_corner2(ivec2(i) + 1, p0 - 1.0 + 2.0 * G2)));
+}
+
+float simplex_corner3(ivec3 i, vec3 d) {
+    float falloff = 0.6 - dot(d, d);
+    return falloff <= 0.0 ? 0.0 : pow(falloff, 4.0) * gradient3(lattice(i), d);
+}
+
+float simplex_noise3(float x, float y, float z) {
+    const float F3 = 1.0 / 3.0;
+    const float G3 = 1.0 / 6.0;
+    vec3 p = vec3(finite_or_zero(x), finite_or_zero(y), finite_or_zero(z));
+    vec3 i = floor(p + (p.x + p.y + p.z) * F3);
+    vec3 p0 = p - (i - (i.x + i.y + i.z) * G3);
+    ivec3 second;
+    ivec3 third;
+    if (p0.x >= p0.y) {
+        if (p0.y >= p0.z) {
+            second = ivec3(1, 0, 0); third = ivec3(1, 1, 0);
+        } else if (p0.x >= p0.z) {
+            second = ivec3(1, 0, 0); third = ivec3(1, 0, 1);
+        } else {
+            second = ivec3(0, 0, 1); third = ivec3(1, 0, 1);
+        }
+    } else if (p0.y < p0.z) {
+        second = ivec3(0, 0, 1); third = ivec3(0, 1, 1);
+    } else if (p0.x < p0.z) {
+        second = ivec3(0, 1, 0); third = ivec3(0, 1, 1);
+    } else {
+        second = ivec3(0, 1, 0); third = ivec3(1, 1, 0);
+    }
+    return unit_noise(32.0 * (simplex_corner3(ivec3(i), p0)
+        + simplex_corner3(ivec3(i) + second, p0 - vec3(second) + G3)
+        + simplex_corner3(ivec3(i) + third, p0 - vec3(third) + 2.0 * G3)
+        + simplex_corner3(ivec3(i) + 1, p0 - 1.0 + 3.0 * G3)));
+}
+
+float fbm(float x, float y, float z, float octaves) {
+    int n = octaves >= 1.0 ? int(min(octaves, 8.0)) : 1;
+    vec3 p = vec3(finite_or_zero(x), finite_or_zero(y), finite_or_zero(z));
+    float sum = 0.0;
+    float total = 0.0;
+    float amplitude = 1.0;
+    for (int octave = 0; octave < n; octave++) {
+        sum += amplitude * perlin_noise3(p.x, p.y, p.z);
+        total += amplitude;
+        amplitude *= 0.5;
+        p *= 2.0;
+    }
+    return unit_noise(sum / total);
+}
+
+";
+
+const SPECTRUM: &str = "float magnitude(float bin) {
+    int bins = textureSize(spectrum, 0).x;
+    return texelFetch(spectrum, ivec2(int(mod(trunc(bin), float(bins))), 0), 0).r;
+}
+
+";
+
+const PREVIOUS_FRAME: &str = "vec3 previous_hsv(float x, float y) {
+    vec2 size = floor(resolution / scale);
+    vec2 p = mod(trunc(vec2(x, y)), size);
+    vec3 c = texture(previous_frame, (vec2(p.x, size.y - 1.0 - p.y) + 0.5) / size).rgb;
+    float top = max(c.r, max(c.g, c.b));
+    float delta = top - min(c.r, min(c.g, c.b));
+    float h = 0.0;
+    if (delta > 0.0) {
+        if (top == c.r) {
+            h = 60.0 * mod((c.g - c.b) / delta, 6.0);
+        } else if (top == c.g) {
+            h = 60.0 * ((c.b - c.r) / delta + 2.0);
+        } else {
+            h = 60.0 * ((c.r - c.g) / delta + 4.0);
+        }
+    }
+    return vec3(h, top > 0.0 ? delta / top : 0.0, top);
+}
+
+";
+
+// Kernels are listed row by row from the top, mirroring renderer.rs
+const CONVOLUTION: &str = "const float BLUR[9] = float[9](
+    0.0625, 0.125, 0.0625,
+    0.125, 0.25, 0.125,
+    0.0625, 0.125, 0.0625);
+const float LAPLACIAN[9] = float[9](
+    0.05, 0.2, 0.05,
+    0.2, -1.0, 0.2,
+    0.05, 0.2, 0.05);
+const float SOBEL_X[9] = float[9](
+    -1.0, 0.0, 1.0,
+    -2.0, 0.0, 2.0,
+    -1.0, 0.0, 1.0);
+const float SOBEL_Y[9] = float[9](
+    -1.0, -2.0, -1.0,
+    0.0, 0.0, 0.0,
+    1.0, 2.0, 1.0);
+
+float previous_channel(float x, float y, int channel) {
+    vec2 size = floor(resolution / scale);
+    vec2 p = mod(trunc(vec2(x, y)), size);
+    return texture(previous_frame, (vec2(p.x, size.y - 1.0 - p.y) + 0.5) / size)[channel];
+}
+
+float convolve(float x, float y, float channel, float kernel[9]) {
+    int c = isinf(channel) || isnan(channel) ? 0 : int(mod(trunc(channel), 3.0));
+    float sum = 0.0;
+    for (int i = 0; i < 9; i++) {
+        sum += kernel[i] * previous_channel(x + float(i % 3) - 1.0, y + float(i / 3) - 1.0, c);
+    }
+    return sum;
+}
+
+float blur(float channel, float x, float y) {
+    return convolve(x, y, channel, BLUR);
+}
+
+float laplacian(float channel, float x, float y) {
+    return convolve(x, y, channel, LAPLACIAN);
+}
+
+float sobel(float channel, float x, float y) {
+    float gx = convolve(x, y, channel, SOBEL_X);
+    float gy = convolve(x, y, channel, SOBEL_Y);
+    return sqrt(gx * gx + gy * gy);
+}
+
+";
+
+const MAIN: &str = "void main() {
+    float x = floor(gl_FragCoord.x / scale);
+    float y = floor((resolution.y - gl_FragCoord.y) / scale);
+    fragColor = program(x, y, t);
+}
+";
+
+/// Compare the traced program against the VM at a grid of sample inputs
+pub fn reference_check(program: &Program, lines: &[Program]) -> anyhow::Result<()> {
+    let symbolic = SymbolicProgram::trace(program, lines)?;
+    let environment = Environment {
+        seed: Some(0),
+        lines: lines.to_vec(),
+        ..Default::default()
+    };
+
+    for x in SAMPLE_XS {
+        for y in SAMPLE_YS {
+            for t in SAMPLE_TS {
+                let mut stack = program.execute([x, y, t], &environment);
+                let expected = [stack.pop(), stack.pop(), stack.pop()];
+                let actual = symbolic.evaluate([x, y, t], &environment);
+                // NaN is as good a result as any, as long as both agree
+                let same = expected
+                    .iter()
+                    .zip(&actual)
+                    .all(|(a, b)| a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan()));
+                if !same {
+                    return Err(anyhow!(
+                        "Shader for {} gives {:?} at ({}, {}, {}), but the VM gives {:?}",
+                        program.source(),
+                        actual,
+                        x,
+                        y,
+                        t,
+                        expected
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn export(args: &ExportArgs, config: &Config) -> anyhow::Result<()> {
+    let (program, lines) = match &args.set {
+        Some(path) => {
+            let set = ProgramSet::parse(&fs::read_to_string(path)?)?;
+            let words = Arc::new(set.words);
+            let lines = set
+                .lines
+                .iter()
+                .map(|line| Program::compile_with_words(line.program.as_str(), &words))
+                .collect();
+            (
+                Program::compile_with_words(args.program.as_str(), &words),
+                lines,
+            )
+        }
+        None => (Program::compile(args.program.as_str()), Vec::new()),
+    };
+    reference_check(&program, &lines)?;
+    let mut colour = config.colour_output();
+    colour.model = args.colour_model.unwrap_or(colour.model);
+    colour.overflow = args.overflow.unwrap_or(colour.overflow);
+    let glsl = SymbolicProgram::trace(&program, &lines)?.to_glsl(program.source(), &colour);
+
+    match &args.output {
+        Some(path) => {
+            fs::write(path, glsl)?;
+            info!("Wrote {}", path.to_string_lossy());
+        }
+        None => print!("{glsl}"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference_check_passes_for_example_programs() {
+        let sources = [
+            include_str!("../programs/example1"),
+            include_str!("../programs/example2"),
+            include_str!("../programs/fractals"),
+        ];
+        for line in sources.iter().flat_map(|source| source.lines()) {
+            reference_check(&Program::compile(line), &[]).unwrap();
+        }
+        reference_check(&Program::compile("rr.bq,f'tdx"), &[]).unwrap();
+        reference_check(&Program::compile("p*xp"), &[]).unwrap();
+        reference_check(&Program::compile("(1+)0*;2"), &[]).unwrap();
+        reference_check(&Program::compile("+60*x3b"), &[]).unwrap();
+        reference_check(&Program::compile("sv*a_o2hgwt"), &[]).unwrap();
+        reference_check(&Program::compile("?<x=d?"), &[]).unwrap();
+        let lines = [
+            Program::compile("*2"),
+            Program::compile("#0+"),
+            Program::compile("#2"),
+        ];
+        reference_check(&Program::compile("#1#2"), &lines).unwrap();
+        assert!(reference_check(&Program::compile("#x"), &lines).is_err());
+        reference_check(&Program::compile("[+q*1.1]5"), &[]).unwrap();
+        reference_check(&Program::compile("[[+1]256]256"), &[]).unwrap();
+        assert!(reference_check(&Program::compile("[+1]"), &[]).is_err());
+        assert!(reference_check(&Program::compile("9".repeat(40)), &[]).is_err());
+    }
+
+    #[test]
+    fn test_underflow_resolves_to_inputs() {
+        // `+` adds x and y, and the last pop wraps around to x
+        let symbolic = SymbolicProgram::trace(&Program::compile("+"), &[]).unwrap();
+        assert_eq!(symbolic.outputs, [3, 2, 0]);
+        assert_eq!(symbolic.nodes[3], Node::Apply(Instruction::Add, vec![0, 1]));
+    }
+
+    #[test]
+    fn test_glsl_uses_ssa_temporaries() {
+        let symbolic = SymbolicProgram::trace(&Program::compile("%c^/m|xe"), &[]).unwrap();
+        let glsl = symbolic.to_glsl("%c^/m|xe", &ColourOutput::default());
+        assert!(glsl.contains("float v3 = x * exp(y);"));
+        assert!(glsl.contains("return colour("));
+        assert!(glsl.contains("vec3 model_rgb(float h, float s, float v)"));
+        assert!(!glsl.contains("random("));
+    }
+
+    #[test]
+    fn test_glsl_operator_expressions() {
+        let glsl = |source: &str| {
+            SymbolicProgram::trace(&Program::compile(source), &[])
+                .unwrap()
+                .to_glsl(source, &ColourOutput::default())
+        };
+
+        // `%` keeps the sign of the dividend, unlike GLSL's mod
+        let modulo = glsl("%");
+        assert!(modulo.contains("float v3 = fmod(x, y);"));
+        assert!(modulo.contains("return a - b * trunc(a / b);"));
+        assert!(!modulo.contains("perlin_noise3("));
+
+        let fract = glsl("o");
+        assert!(fract.contains("float v3 = safe_fract(x);"));
+        assert!(fract.contains("return isinf(a) || isnan(a) ? 0.0 : fract(a);"));
+
+        assert!(glsl("g").contains("float v3 = pow(abs(x), y);"));
+        assert!(glsl("=").contains("float v3 = float(abs(x - y) < 0.001);"));
+        // The condition is popped first, then the value for false
+        assert!(glsl("?").contains("float v3 = x != 0.0 ? t : y;"));
+
+        let noise = glsl("u");
+        assert!(noise.contains("float v3 = unit_noise(perlin_noise3(x, y, t));"));
+        assert!(noise.contains("float perlin_noise3(float x, float y, float z) {"));
+        assert!(noise.contains("float unit_noise(float n) {"));
+
+        // HSV wraps the hue with the Rust remainder, as colour.rs does
+        assert!(modulo.contains("float k = fmod(n + h / 60.0, 6.0);"));
+        assert!(modulo.contains("k = clamp(min(k, 4.0 - k), 0.0, 1.0);"));
+        assert!(modulo.contains(
+            "return vec3(hsv_channel(5.0, h, s, v), hsv_channel(3.0, h, s, v), hsv_channel(1.0, h, s, v));"
+        ));
+    }
+}
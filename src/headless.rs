@@ -0,0 +1,171 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use log::info;
+use raylib::prelude::*;
+
+use crate::app::AppState;
+use crate::app::InputProvider;
+use crate::app::TimeProvider;
+use crate::config::Config;
+use crate::random::RandomMode;
+use crate::recorder;
+use crate::renderer;
+
+#[derive(clap::Args)]
+pub struct RenderArgs {
+    /// Program set as saved with C-s, or a plain text file of programs
+    program: PathBuf,
+    /// Video file to write
+    #[arg(short, long, default_value = "output.mp4")]
+    output: PathBuf,
+    /// Width of the video in pixels
+    #[arg(long, default_value_t = 640)]
+    width: u32,
+    /// Height of the video in pixels
+    #[arg(long, default_value_t = 480)]
+    height: u32,
+    /// Length of the video in seconds
+    #[arg(short, long, default_value_t = 10.0)]
+    duration: f32,
+    #[arg(long, default_value_t = 60)]
+    fps: u32,
+    /// Seed for the `r` operator and the sequencer. Defaults to the seed in
+    /// config.toml, or 0
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Size of each program pixel in video pixels. Defaults to the scale in
+    /// config.toml
+    #[arg(long)]
+    scale: Option<u32>,
+    /// Keep the transparency of programs that leave an opacity, rather than
+    /// drawing them over the background. The output must be a .mov file,
+    /// written as ProRes 4444, or a PNG sequence such as frames/%05d.png
+    #[arg(long)]
+    transparent: bool,
+}
+
+/// Stands in for the window, advancing time by exactly one video frame per
+/// update and never pressing any keys
+struct FixedFrameProvider {
+    frame_time: f32,
+}
+
+impl InputProvider for FixedFrameProvider {
+    fn is_key_down(&self, _key: KeyboardKey) -> bool {
+        false
+    }
+    fn is_key_pressed(&self, _key: KeyboardKey) -> bool {
+        false
+    }
+    fn is_key_pressed_repeat(&self, _key: KeyboardKey) -> bool {
+        false
+    }
+    fn get_key_pressed(&mut self) -> Option<KeyboardKey> {
+        None
+    }
+    fn get_char_pressed(&mut self) -> Option<char> {
+        None
+    }
+}
+
+impl TimeProvider for FixedFrameProvider {
+    fn get_frame_time(&self) -> f32 {
+        self.frame_time
+    }
+}
+
+pub fn render(args: &RenderArgs, mut config: Config) -> anyhow::Result<()> {
+    config.seed = Some(args.seed.or(config.seed).unwrap_or(0));
+    if config.random_mode() == RandomMode::Thread {
+        info!("Using hashed random values so the render can be reproduced");
+        config.random = Some(RandomMode::Hash);
+    }
+    let scale = args.scale.unwrap_or(config.scale);
+    if scale == 0 || !args.width.is_multiple_of(scale) || !args.height.is_multiple_of(scale) {
+        return Err(anyhow!(
+            "Video size {}x{} must be a multiple of the scale {}",
+            args.width,
+            args.height,
+            scale
+        ));
+    }
+    if args.width == 0 || args.height == 0 {
+        return Err(anyhow!(
+            "Video size {}x{} must not be empty",
+            args.width,
+            args.height
+        ));
+    }
+    if args.fps == 0 {
+        return Err(anyhow!("fps must be greater than 0"));
+    }
+    if !args.duration.is_finite() || args.duration <= 0.0 {
+        return Err(anyhow!("Duration must be a positive number of seconds"));
+    }
+
+    let scaled_width = (args.width / scale) as i32;
+    let scaled_height = (args.height / scale) as i32;
+
+    let background = config.background.into();
+    let mut app_state = AppState::new(&config, scaled_width, scaled_height);
+    app_state.load_programs(fs::read_to_string(&args.program)?)?;
+
+    let mut provider = FixedFrameProvider {
+        frame_time: 1.0 / args.fps as f32,
+    };
+    let num_frames = (args.duration * args.fps as f32).round() as u32;
+
+    let (width, height) = (scaled_width as u32, scaled_height as u32);
+    let mut command = if args.transparent {
+        recorder::transparent_encoder_command(&args.output, width, height, args.fps)?
+    } else {
+        recorder::encoder_command("rgba", width, height, args.fps)
+    };
+    let mut child = command
+        .args([
+            "-vf",
+            format!("scale={}:{}:flags=neighbor", args.width, args.height).as_str(),
+        ])
+        .output(&args.output)
+        .overwrite()
+        .spawn()?;
+
+    // ffmpeg blocks once its log pipe is full, so drain it while we write
+    let mut stderr = child
+        .take_stderr()
+        .ok_or(anyhow!("Could not read ffmpeg output"))?;
+    std::thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::sink()));
+
+    let mut stdin = child
+        .take_stdin()
+        .ok_or(anyhow!("Could not write to ffmpeg"))?;
+    let mut frame = renderer::Frame::new(scaled_width, scaled_height);
+
+    for i in 0..num_frames {
+        app_state.update(&mut provider)?;
+        app_state.set_previous_frame(&frame);
+        frame.render(app_state.shader(0.0));
+        if args.transparent {
+            stdin.write_all(frame.pixels())?;
+        } else {
+            stdin.write_all(&frame.composite(background))?;
+        }
+
+        if (i + 1) % args.fps == 0 {
+            info!("Rendered {}/{} frames", i + 1, num_frames);
+        }
+    }
+
+    drop(stdin);
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(anyhow!("ffmpeg exited with {}", status));
+    }
+
+    info!("Wrote {}", args.output.to_string_lossy());
+
+    Ok(())
+}
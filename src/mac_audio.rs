@@ -1,15 +1,21 @@
 use anyhow::anyhow;
 use log::info;
-use std::{ffi::c_void, ptr::NonNull};
+use std::{ffi::c_void, ptr::NonNull, sync::Mutex};
+
+use crate::audio::{AudioSource, SampleHistory};
+use crate::spectrum::FFT_SIZE;
 
 use objc2::{rc::Retained, runtime::AnyObject, MainThreadMarker};
 use objc2_core_audio::{
     kAudioAggregateDeviceIsPrivateKey, kAudioAggregateDeviceNameKey,
     kAudioAggregateDeviceTapAutoStartKey, kAudioAggregateDeviceTapListKey,
-    kAudioAggregateDeviceUIDKey, kAudioSubTapDriftCompensationKey, kAudioSubTapUIDKey,
-    AudioDeviceCreateIOProcID, AudioDeviceDestroyIOProcID, AudioDeviceIOProcID, AudioDeviceStart,
-    AudioDeviceStop, AudioHardwareCreateAggregateDevice, AudioHardwareCreateProcessTap,
-    AudioHardwareDestroyProcessTap, AudioObjectID, CATapDescription, CATapMuteBehavior,
+    kAudioAggregateDeviceUIDKey, kAudioDevicePropertyNominalSampleRate,
+    kAudioObjectPropertyElementMain, kAudioObjectPropertyScopeGlobal,
+    kAudioSubTapDriftCompensationKey, kAudioSubTapUIDKey, AudioDeviceCreateIOProcID,
+    AudioDeviceDestroyIOProcID, AudioDeviceIOProcID, AudioDeviceStart, AudioDeviceStop,
+    AudioHardwareCreateAggregateDevice, AudioHardwareCreateProcessTap,
+    AudioHardwareDestroyProcessTap, AudioObjectGetPropertyData, AudioObjectID,
+    AudioObjectPropertyAddress, CATapDescription, CATapMuteBehavior,
 };
 use objc2_core_audio_types::{AudioBufferList, AudioTimeStamp};
 use objc2_core_foundation::CFDictionary;
@@ -19,17 +25,27 @@ fn cstr_to_nsstring(cstr: &std::ffi::CStr) -> Retained<NSString> {
     NSString::from_str(cstr.to_str().unwrap())
 }
 
+/// Written to by the IO proc on the audio thread
+struct TapData {
+    peak: f32,
+    history: Mutex<SampleHistory>,
+}
+
 pub struct VisualiserAudioTap {
     tap_id: AudioObjectID,
     aggregate_device_id: AudioObjectID,
     tap_io_proc_id: AudioDeviceIOProcID,
-    peak_ptr: *mut f32,
+    data_ptr: *mut TapData,
+    sample_rate: u32,
 }
 
 impl VisualiserAudioTap {
     pub fn setup() -> anyhow::Result<Self> {
         info!("Setting up macOS CoreAudio tap");
-        let peak_ptr = Box::into_raw(Box::new(0.0_f32));
+        let data_ptr = Box::into_raw(Box::new(TapData {
+            peak: 0.0,
+            history: Mutex::new(SampleHistory::new(FFT_SIZE)),
+        }));
 
         let mtm = MainThreadMarker::new().unwrap();
 
@@ -99,7 +115,7 @@ impl VisualiserAudioTap {
             if AudioDeviceCreateIOProcID(
                 aggregate_device_id,
                 Some(ioproc_callback),
-                peak_ptr as *mut c_void,
+                data_ptr as *mut c_void,
                 NonNull::new_unchecked(&raw mut tap_io_proc_id),
             ) != 0
             {
@@ -111,17 +127,55 @@ impl VisualiserAudioTap {
             }
         }
 
+        let sample_rate = nominal_sample_rate(aggregate_device_id).unwrap_or(48000.0) as u32;
+        info!("CoreAudio tap running at {} Hz", sample_rate);
+
         Ok(Self {
             tap_id,
             aggregate_device_id,
             tap_io_proc_id,
-            peak_ptr,
+            data_ptr,
+            sample_rate,
         })
     }
+}
+
+fn nominal_sample_rate(device_id: AudioObjectID) -> Option<f64> {
+    let address = AudioObjectPropertyAddress {
+        mSelector: kAudioDevicePropertyNominalSampleRate,
+        mScope: kAudioObjectPropertyScopeGlobal,
+        mElement: kAudioObjectPropertyElementMain,
+    };
+    let mut sample_rate = 0.0_f64;
+    let mut size = std::mem::size_of::<f64>() as u32;
+    let error = unsafe {
+        AudioObjectGetPropertyData(
+            device_id,
+            NonNull::from(&address),
+            0,
+            std::ptr::null(),
+            NonNull::from(&mut size),
+            NonNull::from(&mut sample_rate).cast(),
+        )
+    };
+    (error == 0 && sample_rate > 0.0).then_some(sample_rate)
+}
 
+impl AudioSource for VisualiserAudioTap {
     /// Get the current peak audio from the pointer. Only call once per frame
-    pub fn audio_peak(&self) -> f32 {
-        unsafe { *self.peak_ptr }
+    fn audio_peak(&mut self, _frame_time: f32) -> f32 {
+        unsafe { (*self.data_ptr).peak }
+    }
+
+    fn recent_samples(&self, samples: &mut [f32]) {
+        let history = unsafe { &(*self.data_ptr).history };
+        if let Ok(history) = history.lock() {
+            history.copy_recent(samples);
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
     }
 }
 
@@ -132,6 +186,7 @@ impl Drop for VisualiserAudioTap {
             AudioDeviceStop(self.aggregate_device_id, self.tap_io_proc_id);
             AudioDeviceDestroyIOProcID(self.aggregate_device_id, self.tap_io_proc_id);
             AudioHardwareDestroyProcessTap(self.tap_id);
+            drop(Box::from_raw(self.data_ptr));
         }
     }
 }
@@ -145,20 +200,29 @@ unsafe extern "C-unwind" fn ioproc_callback(
     _in_output_time: NonNull<AudioTimeStamp>,
     in_client_data: *mut c_void,
 ) -> i32 {
-    let peak_ptr = in_client_data as *mut f32;
+    let data_ptr = in_client_data as *mut TapData;
 
     let in_input_data_ref = unsafe { in_input_data.as_ref() };
     let num_buffers = in_input_data_ref.mNumberBuffers;
 
     let mut volume_total = 0.0_f32;
-    for buffer in 0..num_buffers {
-        let buffer = in_input_data_ref.mBuffers[buffer as usize];
+    for buffer_index in 0..num_buffers {
+        let buffer = in_input_data_ref.mBuffers[buffer_index as usize];
         let num_channels = buffer.mNumberChannels as usize;
         let num_frames = buffer.mDataByteSize as usize / std::mem::size_of::<f32>();
         let num_frames_per_channel = num_frames / num_channels;
 
         let data = buffer.mData as *mut f32;
 
+        // Only keep the first buffer's samples for the spectrum. Don't wait
+        // for the main thread if it is reading them, just skip this block
+        if buffer_index == 0
+            && let Ok(mut history) = unsafe { &(*data_ptr).history }.try_lock()
+        {
+            let samples = unsafe { std::slice::from_raw_parts(data, num_frames) };
+            history.push_interleaved(samples, num_channels);
+        }
+
         for channel in 0..num_channels {
             let mut volume_accum = 0.0;
             for frame in 0..num_frames_per_channel {
@@ -171,7 +235,7 @@ unsafe extern "C-unwind" fn ioproc_callback(
     }
 
     unsafe {
-        peak_ptr.write(volume_total);
+        (&raw mut (*data_ptr).peak).write(volume_total);
     }
 
     0
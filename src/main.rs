@@ -1,32 +1,74 @@
+mod analysis;
 mod animation;
 mod app;
+mod audio;
+mod automaton;
+mod colour;
 mod config;
 mod drawing;
+mod file_audio;
+mod glsl;
+mod headless;
 #[cfg(target_os = "macos")]
 mod mac_audio;
+mod noise;
 mod program;
+mod programset;
+mod random;
 mod recorder;
+mod registers;
+mod renderer;
 mod ringbuffer;
 mod sound;
+mod spectrum;
+mod stdin_audio;
 mod texteditor;
 mod utils;
+mod words;
 
 use crate::drawing::draw_text;
-use std::sync::mpsc;
 
+use clap::Parser;
+use clap::Subcommand;
 use raylib::prelude::*;
 
 const DEJAVU_SANS: &[u8] = include_bytes!("DejaVuSans.ttf");
 
 const MAX_SAMPLES_PER_UPDATE: u32 = 2048;
 
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render a program file to a video without opening a window
+    Render(headless::RenderArgs),
+    /// Export a program line as a GLSL fragment shader
+    Glsl(glsl::ExportArgs),
+}
+
 fn main() -> anyhow::Result<()> {
     colog::init();
 
-    ffmpeg_sidecar::download::auto_download()?;
+    let cli = Cli::parse();
 
     let config = config::Config::from_file("config.toml")?;
 
+    match cli.command {
+        Some(Command::Render(args)) => {
+            ffmpeg_sidecar::download::auto_download()?;
+            return headless::render(&args, config);
+        }
+        Some(Command::Glsl(args)) => return glsl::export(&args, &config),
+        None => {}
+    }
+
+    ffmpeg_sidecar::download::auto_download()?;
+
     let (mut rl, thread) = raylib::init()
         .size(640, 480)
         .vsync()
@@ -45,8 +87,6 @@ fn main() -> anyhow::Result<()> {
 
     let screen_recorder_length = config.video_frames as usize;
 
-    let (progress_sender, progress_receiver) = mpsc::channel();
-
     let ra = raylib::core::audio::RaylibAudio::init_audio_device()?;
     ra.set_audio_stream_buffer_size_default(MAX_SAMPLES_PER_UPDATE as i32);
     let mut stream = ra.new_audio_stream(44100, 16, 1);
@@ -55,24 +95,19 @@ fn main() -> anyhow::Result<()> {
     }
     let mut data = [0i16; MAX_SAMPLES_PER_UPDATE as usize];
 
-    let mut app_state = crate::app::AppState::new(
-        screen_recorder_length,
-        progress_sender,
-        progress_receiver,
-        config.cycle_time,
-        config.pause_fraction,
-        config.primary_colour,
-        scaled_width,
-        scaled_height,
-    );
+    let mut app_state = crate::app::AppState::new(&config, scaled_width, scaled_height);
 
-    #[cfg(target_os = "macos")]
-    let visualiser_audio_tap = mac_audio::VisualiserAudioTap::setup()?;
+    let mut frame_renderer =
+        renderer::FrameRenderer::new(&mut rl, &thread, scaled_width, scaled_height)?;
+
+    let mut audio_source = audio::from_config(&config.audio)?;
+    let mut spectrum_analyser = spectrum::SpectrumAnalyser::new();
 
     let mut frames: u64 = 0;
 
     while !rl.window_should_close() {
-        let audio_peak = visualiser_audio_tap.audio_peak();
+        let audio_peak = audio_source.audio_peak(rl.get_frame_time());
+        app_state.set_spectrum(spectrum_analyser.analyse(audio_source.as_ref()));
         let fps = 1.0 / rl.get_frame_time();
 
         let mouse_position = rl.get_mouse_position();
@@ -84,22 +119,28 @@ fn main() -> anyhow::Result<()> {
         app_state.update(&mut rl)?;
 
         if config.sound && stream.is_processed() {
-            frames = sound::fill_buffer(&mut data, app_state.current_input_line(), frames, mx, my);
+            frames = sound::fill_buffer(
+                &mut data,
+                app_state.current_program(),
+                app_state.environment(),
+                frames,
+                mx,
+                my,
+            );
             stream.update(&data[..MAX_SAMPLES_PER_UPDATE as usize / 2]);
         }
 
+        app_state.set_previous_frame(frame_renderer.frame());
+        frame_renderer.render(app_state.shader(audio_peak))?;
+
         {
             let mut d = rl.begin_drawing(&thread);
-            d.clear_background(Color::WHITE);
+            d.clear_background(Color::from(config.background));
 
-            for y in 0..scaled_height {
-                for x in 0..scaled_width {
-                    let colour = app_state.execute(x, y, audio_peak);
-                    d.draw_rectangle(x * scale, y * scale, scale, scale, colour);
-                }
-            }
+            frame_renderer.draw(&mut d, scale);
 
             app_state.draw_input_text(&mut d, &font, 30, 20, 40);
+            app_state.draw_words(&mut d, &font, width - 30, 20, 30);
             if config.show_fps {
                 draw_text(
                     &mut d,
@@ -0,0 +1,286 @@
+//! Coherent noise for the noise operators. Every function returns a value in
+//! `[0, 1]`, and lattice points are hashed with plain integer arithmetic so
+//! exported shaders can compute the same noise. Coordinates that aren't
+//! finite count as 0.
+
+/// Most octaves `~` sums, however many are asked for
+pub const MAX_OCTAVES: u32 = 8;
+
+// Skew factors for the simplex grids
+const F2: f32 = 0.366_025_42; // (sqrt(3) - 1) / 2
+const G2: f32 = 0.211_324_87; // (3 - sqrt(3)) / 6
+const F3: f32 = 1.0 / 3.0;
+const G3: f32 = 1.0 / 6.0;
+
+pub fn value2(x: f32, y: f32) -> f32 {
+    value3(x, y, 0.0)
+}
+
+pub fn value3(x: f32, y: f32, z: f32) -> f32 {
+    let (xi, xf) = cell(x);
+    let (yi, yf) = cell(y);
+    let (zi, zf) = cell(z);
+    let (u, v, w) = (fade(xf), fade(yf), fade(zf));
+
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        lattice_value(
+            xi.wrapping_add(dx),
+            yi.wrapping_add(dy),
+            zi.wrapping_add(dz),
+        )
+    };
+    let face = |dz: i32| {
+        lerp(
+            lerp(corner(0, 0, dz), corner(1, 0, dz), u),
+            lerp(corner(0, 1, dz), corner(1, 1, dz), u),
+            v,
+        )
+    };
+    lerp(face(0), face(1), w)
+}
+
+pub fn perlin2(x: f32, y: f32) -> f32 {
+    let (xi, xf) = cell(x);
+    let (yi, yf) = cell(y);
+    let (u, v) = (fade(xf), fade(yf));
+
+    let corner = |dx: i32, dy: i32| {
+        gradient2(
+            lattice(xi.wrapping_add(dx), yi.wrapping_add(dy), 0),
+            xf - dx as f32,
+            yf - dy as f32,
+        )
+    };
+    unit(lerp(
+        lerp(corner(0, 0), corner(1, 0), u),
+        lerp(corner(0, 1), corner(1, 1), u),
+        v,
+    ))
+}
+
+pub fn perlin3(x: f32, y: f32, z: f32) -> f32 {
+    unit(signed_perlin3(x, y, z))
+}
+
+/// Perlin noise in roughly `[-1, 1]`, before it is moved into `[0, 1]`
+fn signed_perlin3(x: f32, y: f32, z: f32) -> f32 {
+    let (xi, xf) = cell(x);
+    let (yi, yf) = cell(y);
+    let (zi, zf) = cell(z);
+    let (u, v, w) = (fade(xf), fade(yf), fade(zf));
+
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        gradient3(
+            lattice(
+                xi.wrapping_add(dx),
+                yi.wrapping_add(dy),
+                zi.wrapping_add(dz),
+            ),
+            xf - dx as f32,
+            yf - dy as f32,
+            zf - dz as f32,
+        )
+    };
+    let face = |dz: i32| {
+        lerp(
+            lerp(corner(0, 0, dz), corner(1, 0, dz), u),
+            lerp(corner(0, 1, dz), corner(1, 1, dz), u),
+            v,
+        )
+    };
+    lerp(face(0), face(1), w)
+}
+
+pub fn simplex2(x: f32, y: f32) -> f32 {
+    let (x, y) = (finite(x), finite(y));
+    let s = (x + y) * F2;
+    let (i, j) = ((x + s).floor(), (y + s).floor());
+    let t = (i + j) * G2;
+    let (x0, y0) = (x - (i - t), y - (j - t));
+    let (i, j) = (i as i32, j as i32);
+
+    // Which of the two triangles in the skewed cell the point is in
+    let (i1, j1) = if x0 > y0 { (1, 0) } else { (0, 1) };
+
+    let corner = |di: i32, dj: i32| {
+        let offset = (di + dj) as f32 * G2;
+        let (dx, dy) = (x0 - di as f32 + offset, y0 - dj as f32 + offset);
+        let falloff = 0.5 - dx * dx - dy * dy;
+        if falloff <= 0.0 {
+            return 0.0;
+        }
+        falloff.powi(4) * gradient2(lattice(i.wrapping_add(di), j.wrapping_add(dj), 0), dx, dy)
+    };
+    unit(70.0 * (corner(0, 0) + corner(i1, j1) + corner(1, 1)))
+}
+
+pub fn simplex3(x: f32, y: f32, z: f32) -> f32 {
+    let (x, y, z) = (finite(x), finite(y), finite(z));
+    let s = (x + y + z) * F3;
+    let (i, j, k) = ((x + s).floor(), (y + s).floor(), (z + s).floor());
+    let t = (i + j + k) * G3;
+    let (x0, y0, z0) = (x - (i - t), y - (j - t), z - (k - t));
+    let (i, j, k) = (i as i32, j as i32, k as i32);
+
+    // Which of the six tetrahedra in the skewed cell the point is in
+    let (second, third) = if x0 >= y0 {
+        if y0 >= z0 {
+            ((1, 0, 0), (1, 1, 0))
+        } else if x0 >= z0 {
+            ((1, 0, 0), (1, 0, 1))
+        } else {
+            ((0, 0, 1), (1, 0, 1))
+        }
+    } else if y0 < z0 {
+        ((0, 0, 1), (0, 1, 1))
+    } else if x0 < z0 {
+        ((0, 1, 0), (0, 1, 1))
+    } else {
+        ((0, 1, 0), (1, 1, 0))
+    };
+
+    let corner = |(di, dj, dk): (i32, i32, i32)| {
+        let offset = (di + dj + dk) as f32 * G3;
+        let dx = x0 - di as f32 + offset;
+        let dy = y0 - dj as f32 + offset;
+        let dz = z0 - dk as f32 + offset;
+        let falloff = 0.6 - dx * dx - dy * dy - dz * dz;
+        if falloff <= 0.0 {
+            return 0.0;
+        }
+        falloff.powi(4)
+            * gradient3(
+                lattice(i.wrapping_add(di), j.wrapping_add(dj), k.wrapping_add(dk)),
+                dx,
+                dy,
+                dz,
+            )
+    };
+    unit(32.0 * (corner((0, 0, 0)) + corner(second) + corner(third) + corner((1, 1, 1))))
+}
+
+/// Octaves of Perlin noise, each at twice the frequency and half the
+/// amplitude of the last. `octaves` is clamped to `[1, MAX_OCTAVES]`
+pub fn fbm(x: f32, y: f32, z: f32, octaves: f32) -> f32 {
+    let octaves = if octaves >= 1.0 {
+        (octaves as u32).min(MAX_OCTAVES)
+    } else {
+        1
+    };
+    let (x, y, z) = (finite(x), finite(y), finite(z));
+
+    let mut sum = 0.0;
+    let mut total = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    for _ in 0..octaves {
+        sum += amplitude * signed_perlin3(x * frequency, y * frequency, z * frequency);
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    unit(sum / total)
+}
+
+fn finite(v: f32) -> f32 {
+    if v.is_finite() { v } else { 0.0 }
+}
+
+/// The lattice cell `v` is in, and how far into it `v` is. Far from the
+/// origin the cell saturates, so neighbouring cells are found with wrapping
+/// arithmetic
+fn cell(v: f32) -> (i32, f32) {
+    let v = finite(v);
+    let floor = v.floor();
+    (floor as i32, v - floor)
+}
+
+fn hash(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^ (h >> 16)
+}
+
+fn lattice(x: i32, y: i32, z: i32) -> u32 {
+    hash(x as u32 ^ hash(y as u32 ^ hash(z as u32)))
+}
+
+fn lattice_value(x: i32, y: i32, z: i32) -> f32 {
+    (lattice(x, y, z) >> 8) as f32 / (1u32 << 24) as f32
+}
+
+/// One of eight directions, dotted with (x, y)
+fn gradient2(hash: u32, x: f32, y: f32) -> f32 {
+    let flip = |bit: u32, v: f32| if hash & bit == 0 { v } else { -v };
+    match hash & 7 {
+        0..=3 => flip(1, x) + flip(2, y),
+        4 | 5 => flip(1, x),
+        _ => flip(1, y),
+    }
+}
+
+/// One of the twelve directions to the edges of a cube, dotted with (x, y, z)
+fn gradient3(hash: u32, x: f32, y: f32, z: f32) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = match h {
+        0..=3 => y,
+        12 | 14 => x,
+        _ => z,
+    };
+    let flip = |bit: u32, v: f32| if h & bit == 0 { v } else { -v };
+    flip(1, u) + flip(2, v)
+}
+
+/// 6t^5 - 15t^4 + 10t^3, which eases in and out of each cell
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Move signed noise into `[0, 1]`
+fn unit(n: f32) -> f32 {
+    (0.5 + 0.5 * n).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_noise_is_in_range_and_smooth() {
+        let noises: [fn(f32, f32, f32) -> f32; 7] = [
+            |x, y, _| value2(x, y),
+            value3,
+            |x, y, _| perlin2(x, y),
+            perlin3,
+            |x, y, _| simplex2(x, y),
+            simplex3,
+            |x, y, z| fbm(x, y, z, 4.0),
+        ];
+        for noise in noises {
+            let mut previous = noise(-3.0, 1.5, 0.25);
+            for i in 1..600 {
+                let x = -3.0 + i as f32 * 0.01;
+                let value = noise(x, 1.5, 0.25);
+                assert!((0.0..=1.0).contains(&value));
+                assert!((value - previous).abs() < 0.1);
+                previous = value;
+            }
+            assert_eq!(noise(f32::NAN, 0.0, f32::INFINITY), noise(0.0, 0.0, 0.0));
+            assert!((0.0..=1.0).contains(&noise(1e12, -1e12, 3e9)));
+        }
+    }
+
+    #[test]
+    fn test_gradient_noise_is_grey_at_lattice_points() {
+        assert_eq!(perlin2(3.0, -7.0), 0.5);
+        assert_eq!(perlin3(3.0, -7.0, 12.0), 0.5);
+        assert_ne!(perlin3(3.5, -7.25, 12.0), 0.5);
+    }
+}
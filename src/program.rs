@@ -1,22 +1,478 @@
+use std::ops::Range;
+use std::sync::Arc;
+
 use raylib::color::Color;
 
+use crate::automaton::Cells;
+use crate::colour::ColourOutput;
+use crate::noise;
+use crate::random::PixelRandom;
+use crate::registers::Registers;
+use crate::renderer::Frame;
 use crate::ringbuffer::RingBuffer;
+use crate::spectrum::Spectrum;
+use crate::words::Words;
 
-pub const ALLOWED: [char; 20] = [
+pub const ALLOWED: [char; 63] = [
     'x', 'd', '.', 't', 'q', '^', '&', '|', '+', '-', '*', '/', 'l', 'e', 'c', 'm', '%', 'r', 'n',
-    'b',
+    'b', 'f', ',', ':', '\'', 'p', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 's', 'v', 'a',
+    '_', 'o', 'h', 'g', 'w', '<', '=', '?', '#', '[', ']', 'i', 'j', 'k', 'u', 'y', 'z', '~', '!',
+    '@', '{', '}', '(', ')', ';',
 ];
 
-pub struct Stack {
+/// A single compiled operator. Each variant corresponds to one of the
+/// characters in [`ALLOWED`], apart from `Literal`, which is a run of
+/// digits, and the loop markers, which come from a matching `[` and `]`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    Literal(f32),
+    /// The `]` of a loop, which is reached first since programs run right
+    /// to left
+    LoopStart,
+    /// The `[` of a loop
+    LoopEnd,
+    Swap,
+    Duplicate,
+    Pop,
+    Tuck,
+    DuplicateUnder,
+    Xor,
+    And,
+    Or,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Log,
+    Exp,
+    Cos,
+    Sin,
+    Sqrt,
+    Abs,
+    Floor,
+    Fract,
+    Atan2,
+    Pow,
+    Min,
+    Max,
+    Modulo,
+    Random,
+    Negate,
+    LessThan,
+    Equal,
+    Select,
+    ValueNoise2,
+    ValueNoise3,
+    PerlinNoise2,
+    PerlinNoise3,
+    SimplexNoise2,
+    SimplexNoise3,
+    Fbm,
+    LockBrightness,
+    Store,
+    Load,
+    Cell,
+    Neighbours,
+    Call,
+    Frequency,
+    Bass,
+    Mid,
+    Treble,
+    PreviousFrame,
+    Blur,
+    Laplacian,
+    Sobel,
+}
+
+impl Instruction {
+    pub fn from_char(c: char) -> Option<Instruction> {
+        let instruction = match c {
+            'x' => Instruction::Swap,
+            'd' => Instruction::Duplicate,
+            '.' => Instruction::Pop,
+            't' => Instruction::Tuck,
+            'q' => Instruction::DuplicateUnder,
+            '^' => Instruction::Xor,
+            '&' => Instruction::And,
+            '|' => Instruction::Or,
+            '+' => Instruction::Add,
+            '-' => Instruction::Subtract,
+            '*' => Instruction::Multiply,
+            '/' => Instruction::Divide,
+            'l' => Instruction::Log,
+            'e' => Instruction::Exp,
+            'c' => Instruction::Cos,
+            's' => Instruction::Sin,
+            'v' => Instruction::Sqrt,
+            'a' => Instruction::Abs,
+            '_' => Instruction::Floor,
+            'o' => Instruction::Fract,
+            'h' => Instruction::Atan2,
+            'g' => Instruction::Pow,
+            'w' => Instruction::Min,
+            'm' => Instruction::Max,
+            '%' => Instruction::Modulo,
+            'r' => Instruction::Random,
+            'n' => Instruction::Negate,
+            '<' => Instruction::LessThan,
+            '=' => Instruction::Equal,
+            '?' => Instruction::Select,
+            'i' => Instruction::ValueNoise2,
+            'j' => Instruction::ValueNoise3,
+            'k' => Instruction::PerlinNoise2,
+            'u' => Instruction::PerlinNoise3,
+            'y' => Instruction::SimplexNoise2,
+            'z' => Instruction::SimplexNoise3,
+            '~' => Instruction::Fbm,
+            'b' => Instruction::LockBrightness,
+            '!' => Instruction::Store,
+            '@' => Instruction::Load,
+            '{' => Instruction::Cell,
+            '}' => Instruction::Neighbours,
+            '#' => Instruction::Call,
+            'f' => Instruction::Frequency,
+            ',' => Instruction::Bass,
+            ':' => Instruction::Mid,
+            '\'' => Instruction::Treble,
+            'p' => Instruction::PreviousFrame,
+            '(' => Instruction::Blur,
+            ')' => Instruction::Laplacian,
+            ';' => Instruction::Sobel,
+            _ => return None,
+        };
+        Some(instruction)
+    }
+}
+
+/// Values closer together than this are equal as far as `=` is concerned
+pub const EQUALITY_EPSILON: f32 = 1e-3;
+
+/// Most times a loop can run, however large its count
+pub const MAX_LOOP_ITERATIONS: u32 = 256;
+
+/// Most instructions executed for a single pixel, including those in loops
+/// and called lines. Once it runs out, the rest of the program is skipped
+pub const INSTRUCTION_BUDGET: u32 = 4096;
+
+/// How deeply `#` can call lines that themselves call lines. Any deeper
+/// calls do nothing
+pub const MAX_CALL_DEPTH: usize = 8;
+
+/// The line called by `#` with the popped value `index`, if there is one
+pub fn line_index(index: f32, num_lines: usize) -> Option<usize> {
+    if !index.is_finite() || index < 0.0 {
+        return None;
+    }
+    let i = index as usize;
+    (i < num_lines).then_some(i)
+}
+
+/// Audio levels are scaled up by this much before being pushed, to be on a
+/// similar scale to the pixel coordinates
+pub const AUDIO_SCALE: f32 = 100.0;
+
+/// State shared by every pixel evaluated in a frame
+#[derive(Clone, Debug, Default)]
+pub struct Environment {
+    /// When set, `r` is derived from the seed and the pixel's initial values
+    /// rather than the thread's random number generator, making frames
+    /// reproducible
+    pub seed: Option<u64>,
+    pub spectrum: Spectrum,
+    /// The frame rendered before this one, sampled by `p`
+    pub previous_frame: Frame,
+    /// Every line of the editor, for `#` to call
+    pub lines: Vec<Program>,
+    /// Each pixel's registers, for `!` and `@`
+    pub registers: Registers,
+    /// Cell states for automaton lines, and for `{` and `}`
+    pub cells: Cells,
+}
+
+/// Compile `source` into instructions in source order, expanding any words
+pub(crate) fn tokenize(source: &str, words: &Words) -> Vec<Instruction> {
+    tokenize_spans(source, words)
+        .into_iter()
+        .map(|(_, instruction)| instruction)
+        .collect()
+}
+
+/// Like [`tokenize`], along with the bytes of `source` each instruction
+/// came from. Every instruction a word expands to comes from the word
+fn tokenize_spans(source: &str, words: &Words) -> Vec<(Range<usize>, Instruction)> {
+    let mut instructions = Vec::new();
+    let literal = |digits: Range<usize>| {
+        let value = source[digits.clone()].parse().unwrap();
+        (digits, Instruction::Literal(value))
+    };
+    // Digits are read left to right, so `60` pushes sixty
+    let mut digits_start = None;
+    for (i, c) in source.char_indices() {
+        if c.is_ascii_digit() {
+            digits_start.get_or_insert(i);
+            continue;
+        }
+        if let Some(start) = digits_start.take() {
+            instructions.push(literal(start..i));
+        }
+        let span = i..i + c.len_utf8();
+        match (c, words.expansion(c)) {
+            ('[', _) => instructions.push((span, Instruction::LoopEnd)),
+            (']', _) => instructions.push((span, Instruction::LoopStart)),
+            (_, Some(expansion)) => instructions.extend(
+                expansion
+                    .iter()
+                    .map(|&instruction| (span.clone(), instruction)),
+            ),
+            (_, None) => instructions
+                .extend(Instruction::from_char(c).map(|instruction| (span, instruction))),
+        }
+    }
+    if let Some(start) = digits_start {
+        instructions.push(literal(start..source.len()));
+    }
+    instructions
+}
+
+/// Drop any `[` or `]` without a partner, given instructions in execution
+/// order
+fn remove_unmatched_loops(
+    instructions: Vec<(Range<usize>, Instruction)>,
+) -> Vec<(Range<usize>, Instruction)> {
+    let mut keep = vec![true; instructions.len()];
+    let mut open = Vec::new();
+    for (i, (_, instruction)) in instructions.iter().enumerate() {
+        match instruction {
+            Instruction::LoopStart => open.push(i),
+            Instruction::LoopEnd => keep[i] = open.pop().is_some(),
+            _ => {}
+        }
+    }
+    for i in open {
+        keep[i] = false;
+    }
+    instructions
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(instruction, keep)| keep.then_some(instruction))
+        .collect()
+}
+
+/// Position of the `LoopEnd` matching the `LoopStart` at `start`
+pub fn matching_loop_end(instructions: &[Instruction], start: usize) -> usize {
+    let mut depth = 0;
+    for (i, instruction) in instructions.iter().enumerate().skip(start) {
+        match instruction {
+            Instruction::LoopStart => depth += 1,
+            Instruction::LoopEnd => {
+                depth -= 1;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => {}
+        }
+    }
+    instructions.len()
+}
+
+/// Number of times a loop runs when `count` is popped at its start
+pub fn loop_count(count: f32) -> u32 {
+    if count.is_finite() && count >= 1.0 {
+        (count as u32).min(MAX_LOOP_ITERATIONS)
+    } else {
+        0
+    }
+}
+
+/// A program line compiled once into instructions, in execution order.
+/// Characters that aren't operators or defined words are skipped.
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    source: String,
+    instructions: Vec<Instruction>,
+    /// The bytes of `source` each instruction came from
+    spans: Vec<Range<usize>>,
+    words: Arc<Words>,
+    colour: ColourOutput,
+    /// Whether the program computes the next state of a cell, given its
+    /// current state on top of the stack
+    automaton: bool,
+}
+
+impl Program {
+    pub fn compile(input: impl Into<String>) -> Program {
+        Program::compile_with_words(input, &Arc::default())
+    }
+
+    pub fn compile_with_words(input: impl Into<String>, words: &Arc<Words>) -> Program {
+        let source = input.into();
+        let mut tokens = tokenize_spans(&source, words);
+        // Programs are executed right to left
+        tokens.reverse();
+        let (spans, instructions) = remove_unmatched_loops(tokens).into_iter().unzip();
+        Program {
+            source,
+            instructions,
+            spans,
+            words: Arc::clone(words),
+            colour: ColourOutput::default(),
+            automaton: false,
+        }
+    }
+
+    /// Compile another program using the same words and settings as this
+    /// one
+    pub fn with_source(&self, input: impl Into<String>) -> Program {
+        Program::compile_with_words(input, &self.words)
+            .with_colour(self.colour.clone())
+            .with_automaton(self.automaton)
+    }
+
+    pub fn with_automaton(mut self, automaton: bool) -> Program {
+        self.automaton = automaton;
+        self
+    }
+
+    pub fn is_automaton(&self) -> bool {
+        self.automaton
+    }
+
+    pub fn with_colour(mut self, colour: ColourOutput) -> Program {
+        self.colour = colour;
+        self
+    }
+
+    pub fn colour(&self) -> &ColourOutput {
+        &self.colour
+    }
+
+    pub fn words(&self) -> &Arc<Words> {
+        &self.words
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.source.is_empty()
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn spans(&self) -> &[Range<usize>] {
+        &self.spans
+    }
+
+    pub fn execute<'a>(&self, initial_values: [f32; 3], environment: &'a Environment) -> Stack<'a> {
+        self.run_on(Stack::new(initial_values, environment), initial_values)
+    }
+
+    /// Like [`Program::execute`], but `!` leaves the registers as they are,
+    /// and automaton lines leave their cells as they are
+    pub fn execute_read_only<'a>(
+        &self,
+        initial_values: [f32; 3],
+        environment: &'a Environment,
+    ) -> Stack<'a> {
+        let mut stack = Stack::new(initial_values, environment);
+        stack.read_only = true;
+        self.run_on(stack, initial_values)
+    }
+
+    fn run_on<'a>(&self, mut stack: Stack<'a>, initial_values: [f32; 3]) -> Stack<'a> {
+        let environment = stack.environment;
+        stack.push(initial_values[2]);
+        stack.push(initial_values[1]);
+        stack.push(initial_values[0]);
+        if self.automaton {
+            let [x, y, _] = initial_values;
+            stack.push(environment.cells.state(x, y));
+        }
+        stack.run(&self.instructions);
+        stack
+    }
+
+    /// The colour of a pixel. Automaton programs leave the cell's next state
+    /// on top, which is also the first value of the colour
+    pub fn execute_to_color(&self, initial_values: [f32; 3], environment: &Environment) -> Color {
+        self.to_color(self.execute(initial_values, environment), initial_values)
+    }
+
+    /// The colour of a pixel, without changing its registers or cell. For
+    /// a program fading out while another one runs on the same pixels
+    pub fn execute_to_color_read_only(
+        &self,
+        initial_values: [f32; 3],
+        environment: &Environment,
+    ) -> Color {
+        self.to_color(
+            self.execute_read_only(initial_values, environment),
+            initial_values,
+        )
+    }
+
+    fn to_color(&self, mut stack: Stack, initial_values: [f32; 3]) -> Color {
+        let environment = stack.environment;
+        if self.automaton {
+            let [x, y, _] = initial_values;
+            let state = stack.pop();
+            if !stack.read_only {
+                environment.cells.set_next(x, y, state);
+            }
+            stack.push(state);
+        }
+        let mut color = self.colour.to_color(stack.pop(), stack.pop(), stack.pop());
+        if self.colour.alpha
+            && let Some(alpha) = stack.pop_remaining()
+        {
+            color.a = ColourOutput::opacity(alpha);
+        }
+        color
+    }
+}
+
+pub struct Stack<'a> {
     stack: Vec<f32>,
     ring_buffer: RingBuffer<f32>,
+    random: Option<PixelRandom>,
+    environment: &'a Environment,
+    /// Number of `#` calls currently being executed
+    call_depth: usize,
+    /// Instructions left before the program is cut short
+    budget: u32,
+    /// Where this pixel's registers start, if it has any
+    registers: Option<usize>,
+    /// The pixel's x and y
+    position: [f32; 2],
+    /// Whether registers and cells are left as they are
+    read_only: bool,
 }
 
-impl Stack {
-    pub fn new(ring_buffer: [f32; 3]) -> Stack {
+impl<'a> Stack<'a> {
+    pub fn new(ring_buffer: [f32; 3], environment: &'a Environment) -> Stack<'a> {
         Stack {
-            stack: Vec::new(),
+            stack: Vec::with_capacity(8),
             ring_buffer: ring_buffer.into_iter().collect(),
+            random: environment
+                .seed
+                .map(|seed| PixelRandom::new(seed, ring_buffer)),
+            environment,
+            call_depth: 0,
+            budget: INSTRUCTION_BUDGET,
+            registers: environment.registers.pixel(ring_buffer[0], ring_buffer[1]),
+            position: [ring_buffer[0], ring_buffer[1]],
+            read_only: false,
+        }
+    }
+
+    fn random(&mut self) -> f32 {
+        match &mut self.random {
+            Some(random) => random.next_f32(),
+            None => rand::random::<f32>(),
         }
     }
 
@@ -35,31 +491,76 @@ impl Stack {
         }
     }
 
+    /// Pop a value left on the stack, without falling back on the ring
+    /// buffer
+    pub fn pop_remaining(&mut self) -> Option<f32> {
+        self.stack.pop()
+    }
+
     pub fn pop2(&mut self) -> (f32, f32) {
         (self.pop(), self.pop())
     }
 
-    pub fn execute(&mut self, instruction: char) {
+    /// Execute instructions in order, running loops and stopping once the
+    /// instruction budget runs out
+    pub fn run(&mut self, instructions: &[Instruction]) {
+        // Loops being run, as the position of the first instruction in their
+        // body and the number of iterations left
+        let mut loops: Vec<(usize, u32)> = Vec::new();
+        let mut pc = 0;
+        while pc < instructions.len() && self.budget > 0 {
+            self.budget -= 1;
+            match instructions[pc] {
+                Instruction::LoopStart => {
+                    let count = loop_count(self.pop());
+                    if count == 0 {
+                        pc = matching_loop_end(instructions, pc);
+                    } else {
+                        loops.push((pc + 1, count));
+                    }
+                }
+                Instruction::LoopEnd => {
+                    if let Some((start, remaining)) = loops.last_mut() {
+                        *remaining -= 1;
+                        if *remaining > 0 {
+                            pc = *start;
+                            continue;
+                        }
+                        loops.pop();
+                    }
+                }
+                instruction => self.execute(instruction),
+            }
+            pc += 1;
+        }
+    }
+
+    pub fn execute(&mut self, instruction: Instruction) {
         match instruction {
+            Instruction::Literal(val) => {
+                self.push(val);
+            }
+            // Loops need the instructions around them, so are handled by `run`
+            Instruction::LoopStart | Instruction::LoopEnd => {}
             // Stack functions
-            'x' => {
+            Instruction::Swap => {
                 // swap
                 let val1 = self.pop();
                 let val2 = self.pop();
                 self.push(val1);
                 self.push(val2);
             }
-            'd' => {
+            Instruction::Duplicate => {
                 // duplicate
                 let val = self.pop();
                 self.push(val);
                 self.push(val);
             }
-            '.' => {
+            Instruction::Pop => {
                 // pop
                 self.pop();
             }
-            't' => {
+            Instruction::Tuck => {
                 // tuck x1 x2 -- x1 x2 x1
                 let val1 = self.pop();
                 let val2 = self.pop();
@@ -67,7 +568,7 @@ impl Stack {
                 self.push(val2);
                 self.push(val1);
             }
-            'q' => {
+            Instruction::DuplicateUnder => {
                 // duplicate under x1 x2 -- x1 x2 x2
                 let val1 = self.pop();
                 let val2 = self.pop();
@@ -76,31 +577,31 @@ impl Stack {
                 self.push(val1);
             }
             // Maths functions
-            '^' => {
+            Instruction::Xor => {
                 let (a, b) = self.pop2();
                 self.push(((a as i32) ^ (b as i32)) as f32);
             }
-            '&' => {
+            Instruction::And => {
                 let (a, b) = self.pop2();
                 self.push(((a as i32) & (b as i32)) as f32);
             }
-            '|' => {
+            Instruction::Or => {
                 let (a, b) = self.pop2();
                 self.push(((a as i32) | (b as i32)) as f32);
             }
-            '+' => {
+            Instruction::Add => {
                 let val = self.pop() + self.pop();
                 self.push(val);
             }
-            '-' => {
+            Instruction::Subtract => {
                 let val = self.pop() - self.pop();
                 self.push(val);
             }
-            '*' => {
+            Instruction::Multiply => {
                 let val = self.pop() * self.pop();
                 self.push(val);
             }
-            '/' => {
+            Instruction::Divide => {
                 let val = self.pop();
                 let mut val2 = self.pop();
                 if val2 == 0.0 {
@@ -108,7 +609,7 @@ impl Stack {
                 }
                 self.push(val / val2);
             }
-            'l' => {
+            Instruction::Log => {
                 let val = self.pop();
                 let mut val2 = self.pop();
                 if val2 < 1.0 {
@@ -116,93 +617,500 @@ impl Stack {
                 }
                 self.push(val * val2.ln());
             }
-            'e' => {
+            Instruction::Exp => {
                 let val = self.pop();
                 let val2 = self.pop();
                 self.push(val * val2.exp());
             }
-            'c' => {
+            Instruction::Cos => {
                 let val = self.pop();
                 let val2 = self.pop();
                 self.push(val * val2.cos());
             }
-            'm' => {
+            Instruction::Sin => {
+                let val = self.pop();
+                let val2 = self.pop();
+                self.push(val * val2.sin());
+            }
+            Instruction::Sqrt => {
+                // Negative values are treated as 0 rather than giving NaN
+                let val = self.pop();
+                self.push(val.max(0.0).sqrt());
+            }
+            Instruction::Abs => {
+                let val = self.pop();
+                self.push(val.abs());
+            }
+            Instruction::Floor => {
+                let val = self.pop();
+                self.push(val.floor());
+            }
+            Instruction::Fract => {
+                // Always in [0, 1), even for negative values. Infinity has no
+                // fractional part, so gives 0 rather than NaN
+                let val = self.pop();
+                if val.is_finite() {
+                    self.push(val - val.floor());
+                } else {
+                    self.push(0.0);
+                }
+            }
+            Instruction::Atan2 => {
+                // Angle of the point (val2, val) in radians
+                let val = self.pop();
+                let val2 = self.pop();
+                self.push(val.atan2(val2));
+            }
+            Instruction::Pow => {
+                // The base is made positive, since a negative base to a
+                // fractional power is NaN
+                let val = self.pop();
+                let val2 = self.pop();
+                self.push(val.abs().powf(val2));
+            }
+            Instruction::Min => {
+                let val = self.pop();
+                let val2 = self.pop();
+                self.push(val.min(val2));
+            }
+            Instruction::Max => {
                 let val = self.pop();
                 let val2 = self.pop();
                 self.push(val.max(val2));
             }
-            '%' => {
+            Instruction::Modulo => {
                 let val = self.pop();
                 let val2 = self.pop();
                 self.push(val % val2);
             }
-            'r' => {
+            Instruction::Random => {
                 let val = self.pop();
                 let val2 = self.pop();
-                self.push(val2 * rand::random::<f32>() + val);
+                let random = self.random();
+                self.push(val2 * random + val);
             }
-            'n' => {
+            Instruction::Negate => {
                 let val = self.pop();
                 self.push(-val);
             }
+            // Comparisons, which push 1 for true and 0 for false
+            Instruction::LessThan => {
+                let val = self.pop();
+                let val2 = self.pop();
+                self.push(if val < val2 { 1.0 } else { 0.0 });
+            }
+            Instruction::Equal => {
+                let val = self.pop();
+                let val2 = self.pop();
+                let equal = (val - val2).abs() < EQUALITY_EPSILON;
+                self.push(if equal { 1.0 } else { 0.0 });
+            }
+            Instruction::Select => {
+                // select a b cond -- a if cond is non-zero, otherwise b
+                let cond = self.pop();
+                let b = self.pop();
+                let a = self.pop();
+                self.push(if cond != 0.0 { a } else { b });
+            }
+            // Noise, which pushes a value in [0, 1] for the point (val, val2)
+            // or (val, val2, val3)
+            Instruction::ValueNoise2 => {
+                let (val, val2) = (self.pop(), self.pop());
+                self.push(noise::value2(val, val2));
+            }
+            Instruction::ValueNoise3 => {
+                let (val, val2, val3) = (self.pop(), self.pop(), self.pop());
+                self.push(noise::value3(val, val2, val3));
+            }
+            Instruction::PerlinNoise2 => {
+                let (val, val2) = (self.pop(), self.pop());
+                self.push(noise::perlin2(val, val2));
+            }
+            Instruction::PerlinNoise3 => {
+                let (val, val2, val3) = (self.pop(), self.pop(), self.pop());
+                self.push(noise::perlin3(val, val2, val3));
+            }
+            Instruction::SimplexNoise2 => {
+                let (val, val2) = (self.pop(), self.pop());
+                self.push(noise::simplex2(val, val2));
+            }
+            Instruction::SimplexNoise3 => {
+                let (val, val2, val3) = (self.pop(), self.pop(), self.pop());
+                self.push(noise::simplex3(val, val2, val3));
+            }
+            Instruction::Fbm => {
+                // fbm z y x octaves -- noise
+                let octaves = self.pop();
+                let (val, val2, val3) = (self.pop(), self.pop(), self.pop());
+                self.push(noise::fbm(val, val2, val3, octaves));
+            }
             // Lock brightness
-            'b' => {
+            Instruction::LockBrightness => {
                 let val = self.pop();
                 let val2 = self.pop();
                 self.push(1.0);
                 self.push(val2);
                 self.push(val);
             }
-            _ => {}
+            // Registers, which keep their values between frames. Outside
+            // the frame there are none, so loads give 0
+            Instruction::Store => {
+                // store value register --
+                let register = self.pop();
+                let value = self.pop();
+                if let (Some(pixel), false) = (self.registers, self.read_only) {
+                    self.environment.registers.store(pixel, register, value);
+                }
+            }
+            Instruction::Load => {
+                let register = self.pop();
+                let value = match self.registers {
+                    Some(pixel) => self.environment.registers.load(pixel, register),
+                    None => 0.0,
+                };
+                self.push(value);
+            }
+            // Cells of the automaton, as of the last generation
+            Instruction::Cell => {
+                // State of the cell val across and val2 down from this one
+                let val = self.pop();
+                let val2 = self.pop();
+                let [x, y] = self.position;
+                self.push(self.environment.cells.state(x + val, y + val2));
+            }
+            Instruction::Neighbours => {
+                let [x, y] = self.position;
+                self.push(self.environment.cells.neighbours(x, y));
+            }
+            Instruction::Call => {
+                // Run the program on line val on this stack, as if it were
+                // written here. Lines are numbered from 0
+                let val = self.pop();
+                let lines = &self.environment.lines;
+                if self.call_depth < MAX_CALL_DEPTH
+                    && let Some(i) = line_index(val, lines.len())
+                {
+                    self.call_depth += 1;
+                    self.run(&lines[i].instructions);
+                    self.call_depth -= 1;
+                }
+            }
+            // Audio
+            Instruction::Frequency => {
+                // Magnitude of the frequency bin at val, wrapping around
+                let val = self.pop();
+                self.push(self.environment.spectrum.magnitude(val) * AUDIO_SCALE);
+            }
+            Instruction::Bass => {
+                self.push(self.environment.spectrum.bass() * AUDIO_SCALE);
+            }
+            Instruction::Mid => {
+                self.push(self.environment.spectrum.mid() * AUDIO_SCALE);
+            }
+            Instruction::Treble => {
+                self.push(self.environment.spectrum.treble() * AUDIO_SCALE);
+            }
+            // Feedback
+            Instruction::PreviousFrame => {
+                // Hue, saturation and value of the previous frame at (val, val2),
+                // with hue on top so that `p` alone redraws the previous frame
+                let val = self.pop();
+                let val2 = self.pop();
+                let [h, s, v] = self.environment.previous_frame.hsv(val, val2);
+                self.push(v);
+                self.push(s);
+                self.push(h);
+            }
+            // Red, green or blue of the previous frame around this pixel,
+            // with channel val wrapping around
+            Instruction::Blur => {
+                let val = self.pop();
+                let [x, y] = self.position;
+                self.push(self.environment.previous_frame.blur(x, y, val));
+            }
+            Instruction::Laplacian => {
+                let val = self.pop();
+                let [x, y] = self.position;
+                self.push(self.environment.previous_frame.laplacian(x, y, val));
+            }
+            Instruction::Sobel => {
+                let val = self.pop();
+                let [x, y] = self.position;
+                self.push(self.environment.previous_frame.sobel(x, y, val));
+            }
         }
     }
 }
 
-pub fn execute_string(input: &str, initial_values: [f32; 3]) -> Stack {
-    let mut stack = Stack::new(initial_values);
-    stack.push(initial_values[2]);
-    stack.push(initial_values[1]);
-    stack.push(initial_values[0]);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for c in input.chars().rev() {
-        stack.execute(c);
+    fn remaining(program: &str, initial_values: [f32; 3]) -> [f32; 3] {
+        let environment = Environment::default();
+        let mut stack = Program::compile(program).execute(initial_values, &environment);
+        [stack.pop(), stack.pop(), stack.pop()]
     }
 
-    stack
-}
+    #[test]
+    fn test_empty_program_leaves_initial_values() {
+        assert_eq!(remaining("", [1.0, 2.0, 3.0]), [1.0, 2.0, 3.0]);
+    }
 
-// Adapted from https://github.com/raysan5/raylib/blob/16a0b966c3640d679a9bce5c11164945cadd0783/src/rtextures.c#L4959
-fn color_from_hsv(h: f32, s: f32, v: f32) -> Color {
-    let mut color = Color::new(0, 0, 0, 255);
-
-    // Red channel
-    let mut k = (5.0 + h / 60.0) % 6.0;
-    let t = 4.0 - k;
-    k = k.min(t);
-    k = k.min(1.0);
-    k = k.max(0.0);
-    color.r = (((v - v * s * k) * 255.0) % 256.0) as u8;
-
-    // Green channel
-    let mut k = (3.0 + h / 60.0) % 6.0;
-    let t = 4.0 - k;
-    k = k.min(t);
-    k = k.min(1.0);
-    k = k.max(0.0);
-    color.g = (((v - v * s * k) * 255.0) % 256.0) as u8;
-
-    // Blue channel
-    let mut k = (1.0 + h / 60.0) % 6.0;
-    let t = 4.0 - k;
-    k = k.min(t);
-    k = k.min(1.0);
-    k = k.max(0.0);
-    color.b = (((v - v * s * k) * 255.0) % 256.0) as u8;
-
-    color
-}
+    #[test]
+    fn test_executes_right_to_left() {
+        // x - y, then divided by t
+        assert_eq!(remaining("/-", [1.0, 2.0, 3.0])[0], -1.0 / 3.0);
+        // x / y, then subtract t
+        assert_eq!(remaining("-/", [1.0, 2.0, 3.0])[0], -2.5);
+    }
+
+    #[test]
+    fn test_underflow_cycles_ring_buffer() {
+        // After the sum the stack is [3, 3], so the last pop wraps to x
+        assert_eq!(remaining("+", [1.0, 2.0, 3.0]), [3.0, 3.0, 1.0]);
+        // Each underflowing pop advances through [x, y, t]
+        assert_eq!(remaining("...", [1.0, 2.0, 3.0]), [1.0, 2.0, 3.0]);
+        assert_eq!(remaining("....", [1.0, 2.0, 3.0]), [2.0, 3.0, 1.0]);
+    }
+
+    #[test]
+    fn test_seeded_random_is_reproducible() {
+        let environment = Environment {
+            seed: Some(7),
+            ..Default::default()
+        };
+        let program = Program::compile("rr");
+        let mut a = program.execute([1.0, 2.0, 3.0], &environment);
+        let mut b = program.execute([1.0, 2.0, 3.0], &environment);
+        assert_eq!(a.pop(), b.pop());
+    }
+
+    #[test]
+    fn test_previous_frame_is_redrawn_by_p() {
+        let mut previous_frame = Frame::new(4, 4);
+        // Pure hues, with saturation and value of 0 or 1, survive the round trip
+        let hsv = ColourOutput::default();
+        previous_frame.render(|x, y| hsv.to_color(x as f32 * 60.0, (y % 2) as f32, 1.0));
+        let environment = Environment {
+            previous_frame,
+            ..Default::default()
+        };
+        let program = Program::compile("p");
+        for (x, y) in [(0, 0), (1, 2), (3, 3)] {
+            let initial_values = [x as f32, y as f32, 0.5];
+            let colour = program.execute_to_color(initial_values, &environment);
+            let i = (y * 4 + x) * 4;
+            assert_eq!(
+                [colour.r, colour.g, colour.b, colour.a],
+                environment.previous_frame.pixels()[i..i + 4]
+            );
+        }
+    }
+
+    #[test]
+    fn test_fourth_value_is_opacity_when_alpha_is_on() {
+        let environment = Environment::default();
+        let colour = ColourOutput {
+            alpha: true,
+            ..Default::default()
+        };
+        let program = Program::compile("1").with_colour(colour.clone());
+        let opaque = Program::compile("").with_colour(colour);
+        assert_eq!(
+            program.execute_to_color([0.0, 0.0, 0.5], &environment).a,
+            127
+        );
+        assert_eq!(
+            opaque.execute_to_color([0.0, 0.0, 0.5], &environment).a,
+            255
+        );
+        let program = Program::compile("1");
+        assert_eq!(
+            program.execute_to_color([0.0, 0.0, 0.5], &environment).a,
+            255
+        );
+    }
+
+    #[test]
+    fn test_registers_survive_between_executions() {
+        let environment = Environment {
+            registers: Registers::new(2, 2),
+            ..Default::default()
+        };
+        // Add 1 to register 5, which wraps around to register 1, and leave
+        // its new value on top
+        let program = Program::compile("@1!1d+1@5");
+        for expected in [1.0, 2.0, 3.0] {
+            let mut stack = program.execute([1.0, 1.0, 0.0], &environment);
+            assert_eq!(stack.pop(), expected);
+        }
+        // Other pixels and pixels outside the frame are unaffected
+        assert_eq!(program.execute([0.0, 1.0, 0.0], &environment).pop(), 1.0);
+        assert_eq!(program.execute([5.0, 1.0, 0.0], &environment).pop(), 0.0);
+
+        // Read only programs see the registers but don't change them
+        let mut stack = program.execute_read_only([1.0, 1.0, 0.0], &environment);
+        assert_eq!(stack.pop(), 3.0);
+        assert_eq!(program.execute([1.0, 1.0, 0.0], &environment).pop(), 4.0);
+    }
+
+    #[test]
+    fn test_automaton_steps_game_of_life() {
+        // A blinker, which flips between horizontal and vertical
+        let mut cells = Cells::new(5, 5, 0);
+        let horizontal = [(1, 2), (2, 2), (3, 2)];
+        let vertical = [(2, 1), (2, 2), (2, 3)];
+        cells.begin_step();
+        for y in 0..5 {
+            for x in 0..5 {
+                let alive = horizontal.contains(&(x, y));
+                cells.set_next(x as f32, y as f32, if alive { 1.0 } else { 0.0 });
+            }
+        }
+        cells.finish_step();
+        let mut environment = Environment {
+            cells,
+            ..Default::default()
+        };
 
-pub fn execute_string_to_color(input: &str, initial_values: [f32; 3]) -> Color {
-    let mut stack = execute_string(input, initial_values);
-    color_from_hsv(stack.pop(), stack.pop(), stack.pop())
+        // Alive with 3 neighbours, or with 2 if already alive
+        let life = Program::compile("|&=2}x=3}").with_automaton(true);
+        environment.cells.begin_step();
+        for y in 0..5 {
+            for x in 0..5 {
+                life.execute_to_color([x as f32, y as f32, 0.0], &environment);
+            }
+        }
+        environment.cells.finish_step();
+        for y in 0..5 {
+            for x in 0..5 {
+                let alive = vertical.contains(&(x, y));
+                let expected = if alive { 1.0 } else { 0.0 };
+                assert_eq!(environment.cells.state(x as f32, y as f32), expected);
+            }
+        }
+
+        // Read only, nothing is written and every cell keeps its state
+        environment.cells.begin_step();
+        for y in 0..5 {
+            for x in 0..5 {
+                life.execute_to_color_read_only([x as f32, y as f32, 0.0], &environment);
+            }
+        }
+        environment.cells.finish_step();
+        for (x, y) in vertical {
+            assert_eq!(environment.cells.state(x as f32, y as f32), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_digits_are_read_as_one_literal() {
+        let program = Program::compile("*60x");
+        assert_eq!(
+            program.instructions,
+            vec![
+                Instruction::Swap,
+                Instruction::Literal(60.0),
+                Instruction::Multiply
+            ]
+        );
+        assert_eq!(remaining("*60", [2.0, 3.0, 4.0])[0], 120.0);
+        assert_eq!(remaining("12", [2.0, 3.0, 4.0]), [12.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_maths_operators_avoid_nan() {
+        assert_eq!(remaining("v", [-4.0, 0.0, 0.0])[0], 0.0);
+        assert_eq!(remaining("v", [9.0, 0.0, 0.0])[0], 3.0);
+        assert_eq!(remaining("o", [-1.25, 0.0, 0.0])[0], 0.75);
+        assert_eq!(remaining("o", [f32::INFINITY, 0.0, 0.0])[0], 0.0);
+        assert_eq!(remaining("g", [-2.0, 0.5, 0.0])[0], 2f32.sqrt());
+        assert_eq!(remaining("_", [-1.5, 0.0, 0.0])[0], -2.0);
+        assert_eq!(remaining("w", [1.0, 2.0, 0.0])[0], 1.0);
+        // atan2 of y = x, x = y
+        assert_eq!(remaining("h", [1.0, -1.0, 0.0])[0], 1f32.atan2(-1.0));
+    }
+
+    #[test]
+    fn test_comparisons_and_select() {
+        assert_eq!(remaining("<", [1.0, 2.0, 0.0])[0], 1.0);
+        assert_eq!(remaining("<", [2.0, 2.0, 0.0])[0], 0.0);
+        assert_eq!(remaining("=", [2.0, 2.0005, 0.0])[0], 1.0);
+        assert_eq!(remaining("=", [2.0, 2.1, 0.0])[0], 0.0);
+        // Stack is [t, y, x] with x on top, so x is the condition
+        assert_eq!(remaining("?", [1.0, 2.0, 3.0])[0], 3.0);
+        assert_eq!(remaining("?", [0.0, 2.0, 3.0])[0], 2.0);
+    }
+
+    #[test]
+    fn test_words_are_expanded_in_place() {
+        let words = Words::new([('A', "+1".to_owned())].into()).unwrap();
+        let program = Program::compile_with_words("2Ax", &Arc::new(words));
+        assert_eq!(
+            program.instructions,
+            vec![
+                Instruction::Swap,
+                Instruction::Literal(1.0),
+                Instruction::Add,
+                Instruction::Literal(2.0)
+            ]
+        );
+        assert_eq!(program.with_source("A").instructions.len(), 2);
+    }
+
+    #[test]
+    fn test_call_runs_line_on_current_stack() {
+        let environment = Environment {
+            lines: vec![
+                Program::compile("*2"),
+                Program::compile("#0+"),
+                Program::compile("#2"),
+            ],
+            ..Default::default()
+        };
+        let remaining = |program: &str| {
+            let mut stack = Program::compile(program).execute([3.0, 4.0, 5.0], &environment);
+            [stack.pop(), stack.pop(), stack.pop()]
+        };
+        // Line 0 doubles x
+        assert_eq!(remaining("#0"), [6.0, 4.0, 5.0]);
+        // Line 1 adds x and y, then calls line 0
+        assert_eq!(remaining("#1"), [14.0, 5.0, 3.0]);
+        // Line 2 calls itself until the depth limit, and missing lines do nothing
+        assert_eq!(remaining("#2"), [3.0, 4.0, 5.0]);
+        assert_eq!(remaining("#9"), [3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn test_loops_repeat_their_body() {
+        // Add y to x, three times
+        assert_eq!(remaining("[+q]3", [1.0, 2.0, 0.0])[0], 7.0);
+        // Nested loops multiply
+        assert_eq!(remaining("[[+1]3]2", [0.0, 0.0, 0.0])[0], 6.0);
+        // A count of zero or less skips the body
+        assert_eq!(remaining("[+1]0", [5.0, 0.0, 0.0])[0], 5.0);
+        // Unmatched brackets are dropped
+        assert_eq!(
+            Program::compile("[1]]").instructions,
+            Program::compile("[1]").instructions
+        );
+    }
+
+    #[test]
+    fn test_budget_stops_long_programs() {
+        // 256 * 256 iterations is far over the budget, so only some run
+        let total = remaining("[[+1]256]256", [0.0, 0.0, 0.0])[0];
+        assert!(total > 0.0 && total < (INSTRUCTION_BUDGET / 2) as f32);
+    }
+
+    #[test]
+    fn test_unknown_characters_are_skipped() {
+        let program = Program::compile("x $n");
+        assert_eq!(program.source(), "x $n");
+        assert_eq!(
+            program.instructions,
+            vec![Instruction::Negate, Instruction::Swap]
+        );
+    }
 }
@@ -0,0 +1,205 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::colour::{ColourModel, Overflow};
+use crate::config::Colour;
+use crate::words::Words;
+
+/// Version written to saved program sets. Files from a newer version are
+/// refused rather than half loaded. Version 2 added words.
+pub const VERSION: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transition {
+    Fade,
+    Dissolve,
+    ProgramDissolve,
+    Cut,
+}
+
+/// Optional settings for a line. Anything left out falls back to config.toml.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LineSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cycle_time: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pause_fraction: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transition: Option<Transition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_multiplier: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub colour_model: Option<ColourModel>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overflow: Option<Overflow>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub palette: Option<Vec<Colour>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpha: Option<bool>,
+    /// Run the line as a cellular automaton
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub automaton: Option<bool>,
+    /// Generations per second of an automaton line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step_rate: Option<f32>,
+}
+
+impl LineSettings {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.cycle_time.is_some_and(|cycle_time| cycle_time <= 0.0) {
+            return Err(anyhow!("cycle_time must be greater than 0"));
+        }
+        if self
+            .pause_fraction
+            .is_some_and(|pause_fraction| !(0.0..1.0).contains(&pause_fraction))
+        {
+            return Err(anyhow!("pause_fraction must be at least 0 and less than 1"));
+        }
+        if self.step_rate.is_some_and(|step_rate| step_rate <= 0.0) {
+            return Err(anyhow!("step_rate must be greater than 0"));
+        }
+        if self.palette.as_ref().is_some_and(Vec::is_empty) {
+            return Err(anyhow!("palette must have at least one colour"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProgramLine {
+    pub program: String,
+    #[serde(flatten)]
+    pub settings: LineSettings,
+}
+
+/// The lines of the editor as saved with C-s:
+///
+/// ```toml
+/// version = 2
+///
+/// [words]
+/// A = "%c^"
+///
+/// [[lines]]
+/// program = "A/m|xe"
+/// name = "intro"
+/// cycle_time = 30.0
+/// transition = "program-dissolve"
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProgramSet {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Words::is_empty")]
+    pub words: Words,
+    #[serde(default)]
+    pub lines: Vec<ProgramLine>,
+}
+
+impl ProgramSet {
+    pub fn new(lines: Vec<ProgramLine>, words: Words) -> ProgramSet {
+        ProgramSet {
+            version: VERSION,
+            words,
+            lines,
+        }
+    }
+
+    /// Parse a saved program set, or a plain text file with one program per
+    /// line as saved by older versions
+    pub fn parse(s: &str) -> anyhow::Result<ProgramSet> {
+        let is_program_set = s
+            .parse::<toml::Table>()
+            .is_ok_and(|table| table.contains_key("version"));
+        if !is_program_set {
+            return Ok(ProgramSet::from_plain_text(s));
+        }
+
+        let set: ProgramSet = toml::from_str(s)?;
+        if set.version > VERSION {
+            return Err(anyhow!(
+                "Program set is version {}, but only versions up to {} are supported",
+                set.version,
+                VERSION
+            ));
+        }
+        for line in &set.lines {
+            line.settings.validate()?;
+        }
+        Ok(set)
+    }
+
+    fn from_plain_text(s: &str) -> ProgramSet {
+        ProgramSet::new(
+            s.lines()
+                .map(|line| ProgramLine {
+                    program: line.to_owned(),
+                    settings: LineSettings::default(),
+                })
+                .collect(),
+            Words::default(),
+        )
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_text_loads_one_program_per_line() {
+        let set = ProgramSet::parse("%c^/m|xe\n\n^|tx.n%l").unwrap();
+        let programs: Vec<&str> = set.lines.iter().map(|l| l.program.as_str()).collect();
+        assert_eq!(programs, ["%c^/m|xe", "", "^|tx.n%l"]);
+        assert!(
+            set.lines
+                .iter()
+                .all(|l| l.settings == LineSettings::default())
+        );
+    }
+
+    #[test]
+    fn test_round_trips_through_toml() {
+        let lines = vec![
+            ProgramLine {
+                program: "%c^/m|xe".to_owned(),
+                settings: LineSettings {
+                    name: Some("intro".to_owned()),
+                    cycle_time: Some(30.0),
+                    transition: Some(Transition::ProgramDissolve),
+                    ..Default::default()
+                },
+            },
+            ProgramLine::default(),
+        ];
+        let words = Words::new([('A', "xd".to_owned())].into()).unwrap();
+        let set = ProgramSet::new(lines, words);
+        let toml = set.to_toml().unwrap();
+        assert!(toml.contains("transition = \"program-dissolve\""));
+        assert!(!toml.contains("pause_fraction"));
+
+        let parsed = ProgramSet::parse(&toml).unwrap();
+        assert_eq!(parsed.lines, set.lines);
+        assert_eq!(parsed.words.definitions(), set.words.definitions());
+    }
+
+    #[test]
+    fn test_newer_or_invalid_sets_are_refused() {
+        assert!(ProgramSet::parse("version = 3\n").is_err());
+        assert!(ProgramSet::parse("version = 2\n[words]\nA = \"xA\"\n").is_err());
+        assert!(ProgramSet::parse("version = 2\n[[lines]]\nname = \"x\"\n").is_err());
+        assert!(
+            ProgramSet::parse("version = 2\n[[lines]]\nprogram = \"x\"\npause_fraction = 1.0\n")
+                .is_err()
+        );
+        assert!(
+            ProgramSet::parse("version = 2\n[[lines]]\nprogram = \"x\"\npalette = []\n").is_err()
+        );
+    }
+}
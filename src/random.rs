@@ -0,0 +1,83 @@
+use serde::Deserialize;
+
+/// Where the `r` operator gets its values from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RandomMode {
+    /// The thread's random number generator. Every frame is different and
+    /// nothing can be reproduced
+    #[default]
+    Thread,
+    /// A new seed is drawn for every frame from a generator seeded with the
+    /// config seed, so frame n is the same on every run even when `t` stands
+    /// still
+    Frame,
+    /// Hashed from the seed and the pixel's `x`, `y` and `t`, so the same
+    /// pixel at the same time always gets the same values
+    Hash,
+}
+
+/// Deterministic random numbers for a single pixel. The stream is derived by
+/// hashing the seed with the pixel's initial `[x, y, t]`, so a pixel always
+/// sees the same values at the same time regardless of which thread
+/// evaluates it
+pub struct PixelRandom {
+    state: u64,
+}
+
+impl PixelRandom {
+    pub fn new(seed: u64, initial_values: [f32; 3]) -> Self {
+        let mut state = mix(seed);
+        for value in initial_values {
+            state = mix(state ^ value.to_bits() as u64);
+        }
+        Self { state }
+    }
+
+    /// Returns a value in `[0, 1)`
+    pub fn next_f32(&mut self) -> f32 {
+        // SplitMix64
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        (mix(self.state) >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_mode_from_config() {
+        #[derive(Deserialize)]
+        struct Config {
+            random: RandomMode,
+        }
+        let config: Config = toml::from_str("random = \"frame\"").unwrap();
+        assert_eq!(config.random, RandomMode::Frame);
+        assert!(toml::from_str::<Config>("random = \"dice\"").is_err());
+    }
+
+    #[test]
+    fn test_same_pixel_same_values() {
+        let mut a = PixelRandom::new(42, [1.0, 2.0, 0.5]);
+        let mut b = PixelRandom::new(42, [1.0, 2.0, 0.5]);
+        for _ in 0..10 {
+            let value = a.next_f32();
+            assert_eq!(value, b.next_f32());
+            assert!((0.0..1.0).contains(&value));
+        }
+    }
+
+    #[test]
+    fn test_different_seed_or_pixel_differs() {
+        let value = PixelRandom::new(42, [1.0, 2.0, 0.5]).next_f32();
+        assert_ne!(value, PixelRandom::new(43, [1.0, 2.0, 0.5]).next_f32());
+        assert_ne!(value, PixelRandom::new(42, [2.0, 1.0, 0.5]).next_f32());
+    }
+}
@@ -1,5 +1,9 @@
+use std::path::Path;
 use std::{io::Write, sync::mpsc};
 
+use anyhow::anyhow;
+
+use ffmpeg_sidecar::command::FfmpegCommand;
 use raylib::texture::{Image, ImageColors};
 
 use crate::ringbuffer::RingBuffer;
@@ -22,6 +26,61 @@ impl ImageData {
     }
 }
 
+/// An ffmpeg command reading raw frames of `pix_fmt` piped to its stdin
+fn raw_input_command(pix_fmt: &str, width: u32, height: u32, fps: u32) -> FfmpegCommand {
+    let mut command = FfmpegCommand::new();
+    command
+        .args([
+            "-f",
+            "rawvideo",
+            "-pix_fmt",
+            pix_fmt,
+            "-s",
+            format!("{}x{}", width, height).as_str(),
+            "-r",
+            fps.to_string().as_str(),
+        ])
+        .input("-");
+    command
+}
+
+/// An ffmpeg command that encodes raw frames of `pix_fmt` piped to its stdin.
+/// Only the output remains to be set
+pub fn encoder_command(pix_fmt: &str, width: u32, height: u32, fps: u32) -> FfmpegCommand {
+    let mut command = raw_input_command(pix_fmt, width, height, fps);
+    command
+        .args(["-crf", "5"])
+        .pix_fmt("yuv420p")
+        .codec_video("libx264");
+    command
+}
+
+/// Like `encoder_command` for RGBA frames, but keeping their transparency.
+/// `output` decides the format: ProRes 4444 for a `.mov` file, or a PNG
+/// sequence for a pattern such as `frames/%05d.png`
+pub fn transparent_encoder_command(
+    output: &Path,
+    width: u32,
+    height: u32,
+    fps: u32,
+) -> anyhow::Result<FfmpegCommand> {
+    let mut command = raw_input_command("rgba", width, height, fps);
+    match output.extension().and_then(|extension| extension.to_str()) {
+        Some("mov") => command
+            .codec_video("prores_ks")
+            .args(["-profile:v", "4444"])
+            .pix_fmt("yuva444p10le"),
+        Some("png") => command.codec_video("png").pix_fmt("rgba"),
+        _ => {
+            return Err(anyhow!(
+                "Transparent video must be written to a .mov file or a .png sequence, not {}",
+                output.to_string_lossy()
+            ));
+        }
+    };
+    Ok(command)
+}
+
 pub struct ScreenRecorderState {
     steps_seen: usize,
     is_saving: bool,
@@ -126,21 +185,7 @@ impl ScreenRecorder {
                     .unwrap();
             }
 
-            let mut child = ffmpeg_sidecar::command::FfmpegCommand::new()
-                .args([
-                    "-f",
-                    "rawvideo",
-                    "-pix_fmt",
-                    "rgb24",
-                    "-s",
-                    format!("{}x{}", width, height).as_str(),
-                    "-r",
-                    "60",
-                ])
-                .input("-")
-                .args(["-crf", "5"])
-                .pix_fmt("yuv420p")
-                .codec_video("libx264")
+            let mut child = encoder_command("rgb24", width, height, 60)
                 .output(filepath)
                 .print_command()
                 .overwrite()
@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Number of registers each pixel has
+pub const NUM_REGISTERS: usize = 4;
+
+/// Values stored by `!` for every pixel, kept from one frame to the next.
+/// Pixels are evaluated in parallel, but each only touches its own
+/// registers, so relaxed atomics are enough to share them between threads
+#[derive(Debug, Default)]
+pub struct Registers {
+    width: i32,
+    height: i32,
+    /// The bits of each value
+    values: Vec<AtomicU32>,
+}
+
+impl Registers {
+    pub fn new(width: i32, height: i32) -> Registers {
+        let len = width as usize * height as usize * NUM_REGISTERS;
+        Registers {
+            width,
+            height,
+            values: (0..len).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    /// Where the registers of the pixel at (x, y) start, if it is inside
+    /// the frame
+    pub fn pixel(&self, x: f32, y: f32) -> Option<usize> {
+        let inside = |v: f32, size: i32| v.is_finite() && v >= 0.0 && v < size as f32;
+        if !inside(x, self.width) || !inside(y, self.height) {
+            return None;
+        }
+        Some((y as usize * self.width as usize + x as usize) * NUM_REGISTERS)
+    }
+
+    pub fn load(&self, pixel: usize, register: f32) -> f32 {
+        f32::from_bits(self.values[pixel + index(register)].load(Ordering::Relaxed))
+    }
+
+    pub fn store(&self, pixel: usize, register: f32, value: f32) {
+        self.values[pixel + index(register)].store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Set every register of every pixel back to 0
+    pub fn clear(&mut self) {
+        for value in &mut self.values {
+            *value.get_mut() = 0;
+        }
+    }
+}
+
+impl Clone for Registers {
+    fn clone(&self) -> Self {
+        Registers {
+            width: self.width,
+            height: self.height,
+            values: self
+                .values
+                .iter()
+                .map(|value| AtomicU32::new(value.load(Ordering::Relaxed)))
+                .collect(),
+        }
+    }
+}
+
+/// The register numbered `register`, wrapping around. Anything that isn't
+/// a finite number is register 0
+fn index(register: f32) -> usize {
+    if register.is_finite() {
+        (register as i64).rem_euclid(NUM_REGISTERS as i64) as usize
+    } else {
+        0
+    }
+}
@@ -0,0 +1,283 @@
+use raylib::prelude::*;
+use rayon::prelude::*;
+
+const BYTES_PER_PIXEL: usize = 4;
+
+/// Number of bands given to each thread in the pool. More bands than
+/// threads lets rayon balance out rows that are more expensive to evaluate
+const BANDS_PER_THREAD: usize = 4;
+
+/// Gaussian blur over the 3x3 neighbourhood
+const BLUR: [[f32; 3]; 3] = [
+    [0.0625, 0.125, 0.0625],
+    [0.125, 0.25, 0.125],
+    [0.0625, 0.125, 0.0625],
+];
+
+/// Weights neighbours by how close they are, as is usual for
+/// reaction-diffusion
+const LAPLACIAN: [[f32; 3]; 3] = [[0.05, 0.2, 0.05], [0.2, -1.0, 0.2], [0.05, 0.2, 0.05]];
+
+/// Horizontal Sobel kernel. The vertical one is its transpose
+const SOBEL: [[f32; 3]; 3] = [[-1.0, 0.0, 1.0], [-2.0, 0.0, 2.0], [-1.0, 0.0, 1.0]];
+
+/// The RGBA pixels of one rendered frame, one per program pixel
+#[derive(Clone, Debug, Default)]
+pub struct Frame {
+    width: i32,
+    height: i32,
+    pixels: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(width: i32, height: i32) -> Frame {
+        Frame {
+            width,
+            height,
+            pixels: vec![0; width as usize * height as usize * BYTES_PER_PIXEL],
+        }
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// The pixels drawn over `background`, so every pixel is opaque
+    pub fn composite(&self, background: Color) -> Vec<u8> {
+        let mut pixels = self.pixels.clone();
+        for pixel in pixels.chunks_exact_mut(BYTES_PER_PIXEL) {
+            let alpha = pixel[3] as u32;
+            let channels = [background.r, background.g, background.b];
+            for (channel, under) in pixel.iter_mut().zip(channels) {
+                *channel = ((*channel as u32 * alpha + under as u32 * (255 - alpha)) / 255) as u8;
+            }
+            pixel[3] = 255;
+        }
+        pixels
+    }
+
+    /// Hue, saturation and value of the pixel at (x, y), wrapping around the
+    /// edges. An empty frame is black everywhere.
+    pub fn hsv(&self, x: f32, y: f32) -> [f32; 3] {
+        if self.pixels.is_empty() || !x.is_finite() || !y.is_finite() {
+            return [0.0; 3];
+        }
+        let x = (x as i64).rem_euclid(self.width as i64) as usize;
+        let y = (y as i64).rem_euclid(self.height as i64) as usize;
+        let i = (y * self.width as usize + x) * BYTES_PER_PIXEL;
+        let [r, g, b] = [0, 1, 2].map(|c| self.pixels[i + c] as f32 / 255.0);
+        hsv_from_rgb(r, g, b)
+    }
+
+    /// Red, green or blue of the pixel at (x, y) in `[0, 1]`, wrapping
+    /// around the edges
+    fn channel(&self, x: f32, y: f32, channel: usize) -> f32 {
+        if self.pixels.is_empty() || !x.is_finite() || !y.is_finite() {
+            return 0.0;
+        }
+        let x = (x as i64).rem_euclid(self.width as i64) as usize;
+        let y = (y as i64).rem_euclid(self.height as i64) as usize;
+        self.pixels[(y * self.width as usize + x) * BYTES_PER_PIXEL + channel] as f32 / 255.0
+    }
+
+    /// `channel` around (x, y) weighted by `kernel`, whose rows run top to
+    /// bottom
+    fn convolve(&self, x: f32, y: f32, channel: f32, kernel: &[[f32; 3]; 3]) -> f32 {
+        let channel = channel_index(channel);
+        let mut sum = 0.0;
+        for (dy, row) in kernel.iter().enumerate() {
+            for (dx, weight) in row.iter().enumerate() {
+                let value = self.channel(x + dx as f32 - 1.0, y + dy as f32 - 1.0, channel);
+                sum += weight * value;
+            }
+        }
+        sum
+    }
+
+    pub fn blur(&self, x: f32, y: f32, channel: f32) -> f32 {
+        self.convolve(x, y, channel, &BLUR)
+    }
+
+    pub fn laplacian(&self, x: f32, y: f32, channel: f32) -> f32 {
+        self.convolve(x, y, channel, &LAPLACIAN)
+    }
+
+    /// Magnitude of the gradient, from the Sobel kernels
+    pub fn sobel(&self, x: f32, y: f32, channel: f32) -> f32 {
+        let transposed = std::array::from_fn(|i| SOBEL.map(|row| row[i]));
+        let gx = self.convolve(x, y, channel, &SOBEL);
+        let gy = self.convolve(x, y, channel, &transposed);
+        (gx * gx + gy * gy).sqrt()
+    }
+
+    /// Fill the frame in parallel with the colour of every pixel
+    pub fn render(&mut self, shader: impl Fn(i32, i32) -> Color + Sync) {
+        render_bands(&mut self.pixels, self.width, self.height, shader);
+    }
+}
+
+/// Channel 0, 1 or 2 for red, green or blue, wrapping around. Anything that
+/// isn't a finite number is red
+fn channel_index(channel: f32) -> usize {
+    if channel.is_finite() {
+        (channel as i64).rem_euclid(3) as usize
+    } else {
+        0
+    }
+}
+
+/// The inverse of the HSV colour model, with hue in degrees
+fn hsv_from_rgb(r: f32, g: f32, b: f32) -> [f32; 3] {
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    let h = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let s = if max > 0.0 { delta / max } else { 0.0 };
+    [h, s, max]
+}
+
+/// Evaluates every pixel of the scaled grid on the rayon thread pool and
+/// uploads the result to the GPU as a single texture
+pub struct FrameRenderer {
+    frame: Frame,
+    texture: Texture2D,
+}
+
+impl FrameRenderer {
+    pub fn new(
+        rl: &mut RaylibHandle,
+        thread: &RaylibThread,
+        width: i32,
+        height: i32,
+    ) -> anyhow::Result<Self> {
+        let image = Image::gen_image_color(width, height, Color::BLACK);
+        let texture = rl.load_texture_from_image(thread, &image)?;
+        Ok(Self {
+            frame: Frame::new(width, height),
+            texture,
+        })
+    }
+
+    /// The last frame rendered
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn render(&mut self, shader: impl Fn(i32, i32) -> Color + Sync) -> anyhow::Result<()> {
+        self.frame.render(shader);
+        self.texture.update_texture(self.frame.pixels())?;
+        Ok(())
+    }
+
+    pub fn draw(&self, d: &mut RaylibDrawHandle, scale: i32) {
+        d.draw_texture_ex(
+            &self.texture,
+            Vector2::new(0.0, 0.0),
+            0.0,
+            scale as f32,
+            Color::WHITE,
+        );
+    }
+}
+
+/// Split `pixels` into bands of whole rows and fill each band in parallel
+/// with the RGBA colour of every pixel
+pub fn render_bands(
+    pixels: &mut [u8],
+    width: i32,
+    height: i32,
+    shader: impl Fn(i32, i32) -> Color + Sync,
+) {
+    let row_bytes = width as usize * BYTES_PER_PIXEL;
+    let num_bands = rayon::current_num_threads() * BANDS_PER_THREAD;
+    let rows_per_band = (height as usize).div_ceil(num_bands).max(1);
+
+    pixels
+        .par_chunks_mut(row_bytes * rows_per_band)
+        .enumerate()
+        .for_each(|(band, chunk)| {
+            for (row, row_pixels) in chunk.chunks_exact_mut(row_bytes).enumerate() {
+                let y = (band * rows_per_band + row) as i32;
+                for (x, pixel) in row_pixels.chunks_exact_mut(BYTES_PER_PIXEL).enumerate() {
+                    let colour = shader(x as i32, y);
+                    pixel.copy_from_slice(&[colour.r, colour.g, colour.b, colour.a]);
+                }
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_bands_covers_every_pixel() {
+        let (width, height) = (7, 53);
+        let mut pixels = vec![0; width as usize * height as usize * BYTES_PER_PIXEL];
+        render_bands(&mut pixels, width, height, |x, y| {
+            Color::new(x as u8, y as u8, 1, 255)
+        });
+
+        for y in 0..height {
+            for x in 0..width {
+                let i = (y * width + x) as usize * BYTES_PER_PIXEL;
+                assert_eq!(&pixels[i..i + 4], &[x as u8, y as u8, 1, 255]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_frame_hsv_wraps_and_inverts_color_from_hsv() {
+        let mut frame = Frame::new(3, 2);
+        frame.render(|x, y| {
+            if (x, y) == (2, 1) {
+                Color::new(255, 0, 255, 255)
+            } else {
+                Color::BLACK
+            }
+        });
+        assert_eq!(frame.hsv(2.0, 1.0), [300.0, 1.0, 1.0]);
+        assert_eq!(frame.hsv(-1.0, -1.0), [300.0, 1.0, 1.0]);
+        assert_eq!(frame.hsv(5.5, 3.0), [300.0, 1.0, 1.0]);
+        assert_eq!(frame.hsv(0.0, 0.0), [0.0, 0.0, 0.0]);
+        assert_eq!(Frame::default().hsv(1.0, 1.0), [0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_convolutions_of_a_single_pixel() {
+        let mut frame = Frame::new(4, 4);
+        frame.render(|x, y| {
+            let on = if (x, y) == (1, 1) { 255 } else { 0 };
+            Color::new(on, 0, 0, 255)
+        });
+        assert_eq!(frame.blur(1.0, 1.0, 0.0), 0.25);
+        assert_eq!(frame.blur(2.0, 2.0, 0.0), 0.0625);
+        assert_eq!(frame.blur(1.0, 1.0, 1.0), 0.0);
+        assert_eq!(frame.laplacian(1.0, 1.0, 0.0), -1.0);
+        assert_eq!(frame.laplacian(1.0, 0.0, 0.0), 0.2);
+        // Wraps around the edges
+        assert_eq!(
+            frame.laplacian(2.0, 2.0, 0.0),
+            frame.laplacian(-2.0, -2.0, 0.0)
+        );
+        assert_eq!(frame.sobel(2.0, 1.0, 0.0), 2.0);
+        assert_eq!(frame.sobel(1.0, 1.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_composite_blends_over_background() {
+        let mut frame = Frame::new(2, 1);
+        frame.render(|x, _| Color::new(255, 0, 0, if x == 0 { 255 } else { 51 }));
+        assert_eq!(
+            frame.composite(Color::new(0, 0, 255, 255)),
+            [255, 0, 0, 255, 51, 0, 204, 255]
+        );
+    }
+}
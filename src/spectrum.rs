@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use rustfft::Fft;
+use rustfft::FftPlanner;
+use rustfft::num_complex::Complex;
+
+use crate::audio::AudioSource;
+
+/// Number of samples analysed each frame. Gives a resolution of about 21 Hz
+/// at 44.1 kHz
+pub const FFT_SIZE: usize = 2048;
+
+/// How much of the previous frame's magnitude is kept, so that peaks decay
+/// smoothly instead of flickering from frame to frame
+const DECAY: f32 = 0.85;
+
+const BASS: (f32, f32) = (20.0, 250.0);
+const MID: (f32, f32) = (250.0, 4000.0);
+const TREBLE: (f32, f32) = (4000.0, 16000.0);
+
+/// Magnitudes of the audio heard this frame, normalised so that a full scale
+/// sine wave has a magnitude of 1
+#[derive(Clone, Debug, Default)]
+pub struct Spectrum {
+    magnitudes: Vec<f32>,
+    bass: f32,
+    mid: f32,
+    treble: f32,
+}
+
+impl Spectrum {
+    /// Magnitude of the frequency bin at `bin`, wrapping around the number
+    /// of bins in either direction
+    pub fn magnitude(&self, bin: f32) -> f32 {
+        if self.magnitudes.is_empty() || !bin.is_finite() {
+            return 0.0;
+        }
+        let i = (bin as i64).rem_euclid(self.magnitudes.len() as i64);
+        self.magnitudes[i as usize]
+    }
+
+    pub fn bass(&self) -> f32 {
+        self.bass
+    }
+
+    pub fn mid(&self) -> f32 {
+        self.mid
+    }
+
+    pub fn treble(&self) -> f32 {
+        self.treble
+    }
+}
+
+pub struct SpectrumAnalyser {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    samples: Vec<f32>,
+    buffer: Vec<Complex<f32>>,
+    spectrum: Spectrum,
+}
+
+impl SpectrumAnalyser {
+    pub fn new() -> Self {
+        let fft = FftPlanner::new().plan_fft_forward(FFT_SIZE);
+        // Hann window
+        let window = (0..FFT_SIZE)
+            .map(|i| {
+                let phase = std::f32::consts::TAU * i as f32 / FFT_SIZE as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+        Self {
+            fft,
+            window,
+            samples: vec![0.0; FFT_SIZE],
+            buffer: vec![Complex::default(); FFT_SIZE],
+            spectrum: Spectrum {
+                magnitudes: vec![0.0; FFT_SIZE / 2],
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Analyse the latest samples heard by `source`. Call once per frame,
+    /// after `AudioSource::audio_peak`
+    pub fn analyse(&mut self, source: &dyn AudioSource) -> &Spectrum {
+        source.recent_samples(&mut self.samples);
+        self.analyse_samples(source.sample_rate());
+        &self.spectrum
+    }
+
+    fn analyse_samples(&mut self, sample_rate: u32) {
+        for ((value, sample), weight) in self.buffer.iter_mut().zip(&self.samples).zip(&self.window)
+        {
+            *value = Complex::new(sample * weight, 0.0);
+        }
+        self.fft.process(&mut self.buffer);
+
+        // The window halves the amplitude, and a real sine wave is split
+        // between the positive and negative frequencies
+        let normalisation = 4.0 / FFT_SIZE as f32;
+        for (magnitude, value) in self.spectrum.magnitudes.iter_mut().zip(&self.buffer) {
+            *magnitude = (value.norm() * normalisation).max(*magnitude * DECAY);
+        }
+
+        let bin_width = sample_rate as f32 / FFT_SIZE as f32;
+        self.spectrum.bass = self.band_energy(BASS, bin_width);
+        self.spectrum.mid = self.band_energy(MID, bin_width);
+        self.spectrum.treble = self.band_energy(TREBLE, bin_width);
+    }
+
+    /// Root mean square of the magnitudes between the two frequencies
+    fn band_energy(&self, (low, high): (f32, f32), bin_width: f32) -> f32 {
+        let magnitudes = &self.spectrum.magnitudes;
+        let low = ((low / bin_width).ceil() as usize).min(magnitudes.len());
+        let high = ((high / bin_width).floor() as usize + 1).clamp(low, magnitudes.len());
+        if low == high {
+            return 0.0;
+        }
+        let sum_of_squares: f32 = magnitudes[low..high].iter().map(|m| m * m).sum();
+        (sum_of_squares / (high - low) as f32).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sine_peaks_in_its_bin() {
+        let sample_rate = 44100;
+        let bin = 50;
+        let frequency = bin as f32 * sample_rate as f32 / FFT_SIZE as f32;
+
+        let mut analyser = SpectrumAnalyser::new();
+        for (i, sample) in analyser.samples.iter_mut().enumerate() {
+            *sample = (std::f32::consts::TAU * frequency * i as f32 / sample_rate as f32).sin();
+        }
+        analyser.analyse_samples(sample_rate);
+
+        let spectrum = &analyser.spectrum;
+        assert!((spectrum.magnitude(bin as f32) - 1.0).abs() < 0.01);
+        assert!(spectrum.magnitude(bin as f32 + 10.0) < 0.01);
+        assert_eq!(
+            spectrum.magnitude(bin as f32),
+            spectrum.magnitude((bin + FFT_SIZE / 2) as f32)
+        );
+        // About 1 kHz
+        assert!(spectrum.mid() > spectrum.bass());
+        assert!(spectrum.mid() > spectrum.treble());
+    }
+}
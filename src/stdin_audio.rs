@@ -0,0 +1,105 @@
+use std::io::Read;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use log::info;
+use log::warn;
+
+use crate::audio;
+use crate::audio::AudioSource;
+use crate::audio::SampleFormat;
+use crate::audio::SampleHistory;
+use crate::spectrum::FFT_SIZE;
+
+/// Listens to raw interleaved PCM piped to stdin, e.g. from
+/// `parec --format=s16le | visualiserbox`
+pub struct StdinAudioSource {
+    received: Arc<Mutex<Vec<f32>>>,
+    channels: usize,
+    sample_rate: u32,
+    peak: f32,
+    history: SampleHistory,
+}
+
+impl StdinAudioSource {
+    pub fn spawn(format: SampleFormat, channels: usize, sample_rate: u32) -> Self {
+        info!(
+            "Reading {:?} audio with {} channels from stdin",
+            format, channels
+        );
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let thread_received = received.clone();
+        std::thread::spawn(move || {
+            let sample_size = match format {
+                SampleFormat::S16le => 2,
+                SampleFormat::F32le => 4,
+            };
+            let mut stdin = std::io::stdin().lock();
+            let mut bytes = vec![0u8; 1024 * sample_size];
+            loop {
+                if let Err(e) = stdin.read_exact(&mut bytes) {
+                    warn!("Stopped reading audio from stdin: {}", e);
+                    return;
+                }
+                let samples = bytes.chunks_exact(sample_size).map(|b| match format {
+                    SampleFormat::S16le => i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+                    SampleFormat::F32le => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                });
+                thread_received.lock().unwrap().extend(samples);
+            }
+        });
+
+        Self {
+            received,
+            channels,
+            sample_rate,
+            peak: 0.0,
+            history: SampleHistory::new(FFT_SIZE),
+        }
+    }
+}
+
+impl AudioSource for StdinAudioSource {
+    fn audio_peak(&mut self, _frame_time: f32) -> f32 {
+        let samples = take_whole_frames(&mut self.received.lock().unwrap(), self.channels);
+        // Keep the last level if nothing new has arrived this frame
+        if samples.len() >= self.channels {
+            self.peak = audio::level(&samples, self.channels);
+            self.history.push_interleaved(&samples, self.channels);
+        }
+        self.peak
+    }
+
+    fn recent_samples(&self, samples: &mut [f32]) {
+        self.history.copy_recent(samples);
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+/// Take every whole frame received so far. Reads don't line up with frames,
+/// so a partial frame is left behind for the rest of it to be appended to
+fn take_whole_frames(received: &mut Vec<f32>, channels: usize) -> Vec<f32> {
+    let whole = received.len() - received.len() % channels;
+    received.drain(..whole).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_frames_wait_for_the_next_read() {
+        let mut received = vec![1.0, 2.0, 3.0, 4.0, 5.0];
+        assert_eq!(take_whole_frames(&mut received, 3), [1.0, 2.0, 3.0]);
+        received.extend([6.0, 7.0, 8.0, 9.0]);
+        assert_eq!(
+            take_whole_frames(&mut received, 3),
+            [4.0, 5.0, 6.0, 7.0, 8.0, 9.0]
+        );
+        assert!(received.is_empty());
+    }
+}
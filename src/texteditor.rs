@@ -1,4 +1,12 @@
+use std::sync::Arc;
+
+use crate::analysis::{self, Analysis};
+use crate::colour::ColourOutput;
+use crate::program::Program;
+use crate::programset::{LineSettings, ProgramLine, ProgramSet};
+use crate::words::Words;
 use crate::{drawing::draw_text, program};
+use rand::Rng;
 use rand::seq::IndexedRandom;
 use raylib::prelude::*;
 
@@ -6,10 +14,50 @@ pub fn line_height(font: &Font, size: i32) -> f32 {
     font.measure_text("M", size as f32, 1.0).y
 }
 
+/// The editor always shows at least this many lines
+const MIN_LINES: usize = 10;
+
+/// Maximum number of steps that can be undone
+const MAX_UNDO_STEPS: usize = 1000;
+
+/// Most programs `randomise_line` tries before settling for one that doesn't
+/// vary over space and time
+const MAX_REROLLS: usize = 100;
+
+#[derive(Clone, PartialEq)]
+struct Snapshot {
+    lines: Vec<String>,
+    settings: Vec<LineSettings>,
+    words: Arc<Words>,
+    cursor: usize,
+    current_line: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Edit {
+    /// Inserting a character at the given line and cursor position
+    Insert(usize, usize),
+    Other,
+}
+
 pub struct TextEditor {
     lines: Vec<String>,
+    /// Settings loaded for each line, kept alongside `lines`
+    settings: Vec<LineSettings>,
+    /// Words defined by the loaded program set
+    words: Arc<Words>,
+    /// The analysis of each line, shown after it
+    analyses: Vec<Analysis>,
+    /// Whether the sequencer passes over degenerate lines
+    skip_degenerate: bool,
+    /// Colour output for lines that don't set their own, to judge random
+    /// lines as they will be drawn
+    colour: ColourOutput,
     cursor: usize,
     current_line: usize,
+    undo_stack: Vec<Snapshot>,
+    redo_stack: Vec<Snapshot>,
+    last_edit: Option<Edit>,
 }
 
 impl std::fmt::Display for TextEditor {
@@ -22,22 +70,75 @@ impl std::fmt::Display for TextEditor {
 impl TextEditor {
     pub fn new() -> Self {
         Self {
-            lines: vec![String::new(); 10],
+            lines: vec![String::new(); MIN_LINES],
+            settings: vec![LineSettings::default(); MIN_LINES],
+            words: Arc::default(),
+            analyses: Vec::new(),
+            skip_degenerate: false,
+            colour: ColourOutput::default(),
             cursor: 0,
             current_line: 0,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            last_edit: None,
         }
     }
 
-    pub fn load_from_string(&mut self, s: impl AsRef<str>) {
-        self.lines = s.as_ref().lines().map(|s| s.to_owned()).collect();
-        if self.lines.len() < 10 {
-            self.lines
-                .append(&mut vec![String::new(); 10 - self.lines.len()]);
+    pub fn load_program_set(&mut self, set: ProgramSet) {
+        self.save_undo_step(Edit::Other);
+        (self.lines, self.settings) = set
+            .lines
+            .into_iter()
+            .map(|line| (line.program, line.settings))
+            .unzip();
+        self.words = Arc::new(set.words);
+        if self.lines.len() < MIN_LINES {
+            self.lines.resize(MIN_LINES, String::new());
+            self.settings.resize(MIN_LINES, LineSettings::default());
         }
         self.cursor = 0;
         self.current_line = 0;
     }
 
+    pub fn program_set(&self) -> ProgramSet {
+        ProgramSet::new(
+            self.lines
+                .iter()
+                .zip(&self.settings)
+                .map(|(program, settings)| ProgramLine {
+                    program: program.clone(),
+                    settings: settings.clone(),
+                })
+                .collect(),
+            Words::clone(&self.words),
+        )
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    pub fn settings(&self) -> &[LineSettings] {
+        &self.settings
+    }
+
+    /// Set the analysis of each line, once the lines have been compiled
+    pub fn set_analyses(&mut self, analyses: Vec<Analysis>) {
+        self.analyses = analyses;
+    }
+
+    pub fn set_skip_degenerate(&mut self, skip_degenerate: bool) {
+        self.skip_degenerate = skip_degenerate;
+    }
+
+    pub fn set_colour(&mut self, colour: ColourOutput) {
+        self.colour = colour;
+    }
+
+    pub fn words(&self) -> &Arc<Words> {
+        &self.words
+    }
+
     pub fn draw(
         &self,
         d: &mut RaylibDrawHandle,
@@ -50,6 +151,7 @@ impl TextEditor {
         let line_height = line_height(font, size) as i32;
 
         self.draw_cursor(d, font, x, y, size, colour);
+        self.draw_analyses(d, font, x, y, size, colour);
 
         for (i, line) in self.lines.iter().enumerate() {
             draw_text(
@@ -68,17 +170,20 @@ impl TextEditor {
         if self.cursor < self.lines[self.current_line].len() {
             self.cursor += 1;
         }
+        self.end_edit();
     }
 
     pub fn move_left(&mut self) {
         if self.cursor > 0 {
             self.cursor -= 1;
         }
+        self.end_edit();
     }
 
     pub fn next_line(&mut self) {
         self.current_line = (self.current_line + 1) % self.lines.len();
         self.clamp_cursor();
+        self.end_edit();
     }
 
     pub fn prev_line(&mut self) {
@@ -88,36 +193,59 @@ impl TextEditor {
             self.current_line -= 1;
         }
         self.clamp_cursor();
+        self.end_edit();
     }
 
     pub fn start_of_line(&mut self) {
         self.cursor = 0;
+        self.end_edit();
     }
 
     pub fn end_of_line(&mut self) {
         self.cursor = self.get_current_line_str().len();
+        self.end_edit();
     }
 
     pub fn insert_char(&mut self, c: char) {
+        self.save_undo_step(Edit::Insert(self.current_line, self.cursor));
         self.lines[self.current_line].insert(self.cursor, c);
     }
 
     pub fn backspace(&mut self) {
         if self.lines[self.current_line].len() > self.cursor {
+            self.save_undo_step(Edit::Other);
             self.lines[self.current_line].remove(self.cursor);
             self.clamp_cursor()
         }
     }
 
     pub fn clear(&mut self) {
+        self.save_undo_step(Edit::Other);
         self.lines[self.current_line].clear();
         self.cursor = 0;
     }
 
     pub fn kill_to_end(&mut self) {
+        self.save_undo_step(Edit::Other);
         self.lines[self.current_line].truncate(self.cursor);
     }
 
+    /// Go back to the state before the last edit. Consecutive characters
+    /// typed at the same place are undone together
+    pub fn undo(&mut self) {
+        if let Some(snapshot) = self.undo_stack.pop() {
+            let current = self.restore(snapshot);
+            self.redo_stack.push(current);
+        }
+    }
+
+    pub fn redo(&mut self) {
+        if let Some(snapshot) = self.redo_stack.pop() {
+            let current = self.restore(snapshot);
+            self.undo_stack.push(current);
+        }
+    }
+
     pub fn current_line(&self) -> usize {
         self.current_line
     }
@@ -126,11 +254,40 @@ impl TextEditor {
         self.lines[self.current_line].as_str()
     }
 
+    pub fn current_settings(&self) -> &LineSettings {
+        &self.settings[self.current_line]
+    }
+
+    /// Replace the current line with a random program, rerolling until its
+    /// output varies over space and time
     pub fn randomise_line(&mut self) {
+        self.randomise_line_with(&mut rand::rng());
+    }
+
+    pub fn randomise_line_with(&mut self, rng: &mut impl Rng) {
         self.clear();
-        let mut rng = rand::rng();
-        let sampled: [char; 8] = program::ALLOWED.choose_multiple_array(&mut rng).unwrap();
-        self.lines[self.current_line] = sampled.iter().collect();
+        let mut lines: Vec<Program> = self
+            .lines
+            .iter()
+            .map(|line| Program::compile_with_words(line.as_str(), &self.words))
+            .collect();
+        let settings = self.current_settings();
+        let colour = self.colour.for_line(settings);
+        let automaton = settings.automaton.unwrap_or(false);
+        for _ in 0..MAX_REROLLS {
+            let sampled: [char; 8] = program::ALLOWED.choose_multiple_array(rng).unwrap();
+            let line: String = sampled.iter().collect();
+            // Compiled as it will be drawn, and in its place for `#`
+            let program = Program::compile_with_words(line.as_str(), &self.words);
+            lines[self.current_line] = program.clone();
+            let program = program
+                .with_colour(colour.clone())
+                .with_automaton(automaton);
+            self.lines[self.current_line] = line;
+            if analysis::analyse(&program, &lines).varies_over_space_and_time() {
+                break;
+            }
+        }
     }
 
     pub fn rotate_line_left(&mut self) {
@@ -139,6 +296,7 @@ impl TextEditor {
             return;
         }
         s.rotate_left(1);
+        self.save_undo_step(Edit::Other);
         self.lines[self.current_line] = s.iter().collect();
     }
 
@@ -148,6 +306,7 @@ impl TextEditor {
             return;
         }
         s.rotate_right(1);
+        self.save_undo_step(Edit::Other);
         self.lines[self.current_line] = s.iter().collect();
     }
 
@@ -155,12 +314,26 @@ impl TextEditor {
         self.lines.iter().filter(|s| !s.is_empty()).count()
     }
 
+    /// Whether the analysis of line `i` found it degenerate
+    fn is_degenerate(&self, i: usize) -> bool {
+        self.analyses.get(i).is_some_and(Analysis::is_degenerate)
+    }
+
+    /// The line after the current one that the sequencer plays next,
+    /// passing over degenerate lines when skipping them, as long as there is
+    /// a line worth playing. If that is only the current line, it plays again
     pub fn get_next_nonempty_index(&self) -> Option<usize> {
         if self.num_non_empty_lines() == 0 {
             return None;
         }
+        let skip = self.skip_degenerate
+            && self
+                .lines
+                .iter()
+                .enumerate()
+                .any(|(i, line)| !line.is_empty() && !self.is_degenerate(i));
         let mut i = (self.current_line + 1) % self.lines.len();
-        while self.lines[i].is_empty() {
+        while self.lines[i].is_empty() || (skip && self.is_degenerate(i)) {
             i = (i + 1) % self.lines.len();
         }
         Some(i)
@@ -173,13 +346,62 @@ impl TextEditor {
         ""
     }
 
+    pub fn get_next_nonempty_settings(&self) -> &LineSettings {
+        &self.settings[self.get_next_nonempty_index().unwrap_or(self.current_line)]
+    }
+
     pub fn goto_next_nonempty(&mut self) {
-        self.next_line();
-        while self.get_current_line_str().is_empty() {
-            self.next_line();
+        if let Some(i) = self.get_next_nonempty_index() {
+            self.current_line = i;
+            self.clamp_cursor();
+            self.end_edit();
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            lines: self.lines.clone(),
+            settings: self.settings.clone(),
+            words: Arc::clone(&self.words),
+            cursor: self.cursor,
+            current_line: self.current_line,
+        }
+    }
+
+    /// Restore a snapshot, returning the state it replaced
+    fn restore(&mut self, snapshot: Snapshot) -> Snapshot {
+        let current = self.snapshot();
+        self.lines = snapshot.lines;
+        self.settings = snapshot.settings;
+        self.words = snapshot.words;
+        self.cursor = snapshot.cursor;
+        self.current_line = snapshot.current_line;
+        self.last_edit = None;
+        current
+    }
+
+    /// Remember the state before an edit so that it can be undone, unless it
+    /// continues a run of inserted characters
+    fn save_undo_step(&mut self, edit: Edit) {
+        let continues_insert = matches!(edit, Edit::Insert(..)) && self.last_edit == Some(edit);
+        self.last_edit = Some(edit);
+        if continues_insert {
+            return;
+        }
+
+        self.redo_stack.clear();
+        self.undo_stack.push(self.snapshot());
+        if self.undo_stack.len() > MAX_UNDO_STEPS {
+            self.undo_stack.remove(0);
         }
     }
 
+    /// Start a new undo step for whatever is typed next, since the cursor
+    /// has moved, even if it has come back to where it was
+    fn end_edit(&mut self) {
+        self.last_edit = None;
+    }
+
     fn clamp_cursor(&mut self) {
         if self.cursor > self.lines[self.current_line].len() {
             self.cursor = self.lines[self.current_line].len();
@@ -215,4 +437,186 @@ impl TextEditor {
             colour.alpha(0.0),
         );
     }
+
+    /// Show the analysis of each line in smaller text after it, and
+    /// underline anything in the line whose results are never used
+    fn draw_analyses(
+        &self,
+        d: &mut RaylibDrawHandle,
+        font: &Font,
+        x: i32,
+        y: i32,
+        size: i32,
+        colour: Color,
+    ) {
+        let note_size = size / 2;
+        let note_height = line_height(font, note_size) as i32;
+        let line_height = line_height(font, size) as i32;
+        let colour = colour.alpha(0.6);
+
+        for (i, (line, analysis)) in self.lines.iter().zip(&self.analyses).enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let line_y = y + i as i32 * line_height;
+            let offset = |end: usize| {
+                let text = line.get(..end).unwrap_or(line);
+                font.measure_text(text, size as f32, 1.0).x as i32
+            };
+            for span in &analysis.dead {
+                let start = offset(span.start);
+                let width = offset(span.end) - start;
+                d.draw_rectangle(x + start, line_y + line_height - 3, width, 2, colour);
+            }
+            draw_text(
+                d,
+                font,
+                analysis.to_string(),
+                x + offset(line.len()) + note_size,
+                line_y + (line_height - note_height) / 2,
+                note_size,
+                colour,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colour::ColourModel;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn type_str(editor: &mut TextEditor, s: &str) {
+        for c in s.chars() {
+            editor.insert_char(c);
+        }
+    }
+
+    #[test]
+    fn test_program_set_keeps_settings_with_lines() {
+        let mut editor = TextEditor::new();
+        let set = ProgramSet::parse(
+            "version = 2\n[[lines]]\nprogram = \"xd\"\n[[lines]]\nprogram = \"+\"\ncycle_time = 5.0\n",
+        )
+        .unwrap();
+        editor.load_program_set(set);
+        editor.next_line();
+        assert_eq!(editor.current_settings().cycle_time, Some(5.0));
+
+        let saved = editor.program_set();
+        assert_eq!(saved.lines.len(), MIN_LINES);
+        assert_eq!(saved.lines[1].program, "+");
+        assert_eq!(saved.lines[1].settings.cycle_time, Some(5.0));
+    }
+
+    #[test]
+    fn test_undo_coalesces_typing() {
+        let mut editor = TextEditor::new();
+        type_str(&mut editor, "xd+");
+        editor.next_line();
+        type_str(&mut editor, "c");
+        editor.undo();
+        assert_eq!(editor.lines[1], "");
+        assert_eq!(editor.lines[0], "+dx");
+        editor.undo();
+        assert_eq!(editor.lines[0], "");
+        assert_eq!(editor.current_line(), 0);
+
+        // Moving away and back starts a new step
+        type_str(&mut editor, "xd");
+        editor.move_right();
+        editor.move_left();
+        type_str(&mut editor, "+");
+        editor.undo();
+        assert_eq!(editor.lines[0], "dx");
+    }
+
+    #[test]
+    fn test_redo_after_destructive_edits() {
+        let mut editor = TextEditor::new();
+        type_str(&mut editor, "tqx");
+        editor.rotate_line_left();
+        editor.clear();
+        editor.undo();
+        assert_eq!(editor.get_current_line_str(), "qtx");
+        editor.undo();
+        assert_eq!(editor.get_current_line_str(), "xqt");
+        editor.redo();
+        editor.redo();
+        assert_eq!(editor.get_current_line_str(), "");
+        // Nothing more to redo
+        editor.redo();
+        assert_eq!(editor.get_current_line_str(), "");
+    }
+
+    #[test]
+    fn test_sequencer_skips_degenerate_lines() {
+        let set_lines = |editor: &mut TextEditor, lines: &[&str]| {
+            editor.lines = lines.iter().map(|line| line.to_string()).collect();
+            let analyses = editor
+                .lines
+                .iter()
+                .map(|line| analysis::analyse(&Program::compile(line.as_str()), &[]))
+                .collect();
+            editor.set_analyses(analyses);
+        };
+        let mut editor = TextEditor::new();
+        // A flat colour, an empty line and pure noise between two others
+        set_lines(&mut editor, &["x", "1 1 60", "", "r0 360 1 1", "+"]);
+        assert_eq!(editor.get_next_nonempty_index(), Some(1));
+
+        editor.set_skip_degenerate(true);
+        editor.goto_next_nonempty();
+        assert_eq!(editor.current_line(), 4);
+        editor.goto_next_nonempty();
+        assert_eq!(editor.current_line(), 0);
+
+        // When the current line is the only one worth playing, it is held
+        set_lines(&mut editor, &["x", "1 1 60", "r0 360 1 1"]);
+        assert_eq!(editor.get_next_nonempty_index(), Some(0));
+        // With nothing worth playing, every line is played
+        set_lines(&mut editor, &["1 1 60", "r0 360 1 1"]);
+        assert_eq!(editor.get_next_nonempty_index(), Some(1));
+    }
+
+    #[test]
+    fn test_randomised_lines_vary() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut editor = TextEditor::new();
+        // A line calling the random one, so random lines using `#1` call
+        // each candidate in turn
+        editor.lines[1] = "#0".to_string();
+        let palette = ColourOutput {
+            model: ColourModel::Palette,
+            ..Default::default()
+        };
+        for colour in [ColourOutput::default(), palette] {
+            editor.set_colour(colour.clone());
+            for _ in 0..20 {
+                editor.randomise_line_with(&mut rng);
+                let lines: Vec<Program> = editor
+                    .lines
+                    .iter()
+                    .map(|line| Program::compile(line.as_str()))
+                    .collect();
+                let program = lines[0].clone().with_colour(colour.clone());
+                assert!(analysis::analyse(&program, &lines).varies_over_space_and_time());
+            }
+        }
+    }
+
+    #[test]
+    fn test_new_edit_clears_redo() {
+        let mut editor = TextEditor::new();
+        type_str(&mut editor, "x");
+        editor.kill_to_end();
+        editor.undo();
+        editor.randomise_line();
+        editor.redo();
+        assert_eq!(editor.get_current_line_str().len(), 8);
+        editor.undo();
+        assert_eq!(editor.get_current_line_str(), "x");
+    }
 }
@@ -0,0 +1,133 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::program::{self, Instruction};
+
+/// Named sub-programs defined in a program set. Each word is an uppercase
+/// letter, and is replaced by its definition wherever it appears in a
+/// program when the program is compiled.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "BTreeMap<char, String>", into = "BTreeMap<char, String>")]
+pub struct Words {
+    definitions: BTreeMap<char, String>,
+    /// Each definition compiled in source order, with any words it uses
+    /// already expanded
+    expansions: HashMap<char, Vec<Instruction>>,
+}
+
+impl Words {
+    pub fn new(definitions: BTreeMap<char, String>) -> anyhow::Result<Words> {
+        if let Some(name) = definitions.keys().find(|name| !name.is_ascii_uppercase()) {
+            return Err(anyhow!("Word {} must be a single uppercase letter", name));
+        }
+
+        let mut words = Words {
+            definitions,
+            expansions: HashMap::new(),
+        };
+        let names: Vec<char> = words.definitions.keys().copied().collect();
+        for name in names {
+            words.expand(name, &mut Vec::new())?;
+        }
+        Ok(words)
+    }
+
+    /// Expand `name` after expanding every word it uses. `path` is the chain
+    /// of words being expanded, so coming across one of them again means the
+    /// definitions are recursive
+    fn expand(&mut self, name: char, path: &mut Vec<char>) -> anyhow::Result<()> {
+        if self.expansions.contains_key(&name) {
+            return Ok(());
+        }
+        if path.contains(&name) {
+            path.push(name);
+            let start = path.iter().position(|&c| c == name).unwrap_or(0);
+            let cycle: Vec<String> = path[start..].iter().map(char::to_string).collect();
+            let cycle = cycle.join(" -> ");
+            return Err(anyhow!(
+                "Word {} is defined in terms of itself: {}",
+                name,
+                cycle
+            ));
+        }
+
+        path.push(name);
+        let definition = self.definitions[&name].clone();
+        for used in definition.chars() {
+            if self.definitions.contains_key(&used) {
+                self.expand(used, path)?;
+            }
+        }
+        path.pop();
+
+        let expansion = program::tokenize(&definition, self);
+        self.expansions.insert(name, expansion);
+        Ok(())
+    }
+
+    pub fn definitions(&self) -> &BTreeMap<char, String> {
+        &self.definitions
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.definitions.is_empty()
+    }
+
+    pub(crate) fn expansion(&self, name: char) -> Option<&[Instruction]> {
+        self.expansions.get(&name).map(Vec::as_slice)
+    }
+}
+
+impl TryFrom<BTreeMap<char, String>> for Words {
+    type Error = anyhow::Error;
+
+    fn try_from(definitions: BTreeMap<char, String>) -> anyhow::Result<Words> {
+        Words::new(definitions)
+    }
+}
+
+impl From<Words> for BTreeMap<char, String> {
+    fn from(words: Words) -> BTreeMap<char, String> {
+        words.definitions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(definitions: &[(char, &str)]) -> anyhow::Result<Words> {
+        Words::new(
+            definitions
+                .iter()
+                .map(|&(name, definition)| (name, definition.to_owned()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_words_expand_nested_definitions() {
+        let words = words(&[('A', "xB"), ('B', "d1")]).unwrap();
+        assert_eq!(
+            words.expansion('A').unwrap(),
+            [
+                Instruction::Swap,
+                Instruction::Duplicate,
+                Instruction::Literal(1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recursive_words_are_refused() {
+        let error = words(&[('A', "xB"), ('B', "dC"), ('C', "A")]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Word A is defined in terms of itself: A -> B -> C -> A"
+        );
+        assert!(words(&[('A', "A")]).is_err());
+        assert!(words(&[('a', "x")]).is_err());
+    }
+}